@@ -0,0 +1,141 @@
+use crate::NormalizedSpell;
+use charms_data::{App, Data, UtxoId};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Spell in the form users write it (as accepted by `charms spell check`): charms are keyed by
+/// app keys (`$0000`, `$0001`, ...) instead of app indices.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DenormalizedSpell {
+    pub version: u32,
+    pub apps: BTreeMap<String, App>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_inputs: Option<BTreeMap<String, Data>>,
+    pub ins: Vec<DenormalizedInput>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refs: Option<Vec<DenormalizedInput>>,
+    pub outs: Vec<DenormalizedOutput>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DenormalizedInput {
+    pub utxo_id: UtxoId,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DenormalizedOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub charms: Option<BTreeMap<String, Data>>,
+}
+
+/// Key of the app at index `i` in a de-normalized spell.
+pub fn app_key(i: usize) -> String {
+    format!("${:04}", i)
+}
+
+/// De-normalize a normalized spell.
+pub fn denormalized(norm_spell: &NormalizedSpell) -> DenormalizedSpell {
+    let apps = (0..)
+        .zip(norm_spell.app_public_inputs.keys())
+        .map(|(i, app)| (app_key(i), app.clone()))
+        .collect();
+
+    let public_inputs: BTreeMap<_, _> = (0..)
+        .zip(norm_spell.app_public_inputs.values())
+        .filter(|(_, data)| !data.is_empty())
+        .map(|(i, data)| (app_key(i), data.clone()))
+        .collect();
+
+    let ins = norm_spell
+        .tx
+        .ins
+        .iter()
+        .flatten()
+        .map(|utxo_id| DenormalizedInput {
+            utxo_id: utxo_id.clone(),
+        })
+        .collect();
+
+    let refs: Vec<_> = norm_spell
+        .tx
+        .refs
+        .iter()
+        .map(|utxo_id| DenormalizedInput {
+            utxo_id: utxo_id.clone(),
+        })
+        .collect();
+
+    let outs = norm_spell
+        .tx
+        .outs
+        .iter()
+        .map(|n_charms| DenormalizedOutput {
+            charms: Some(
+                n_charms
+                    .iter()
+                    .map(|(&i, data)| (app_key(i), data.clone()))
+                    .collect::<BTreeMap<_, _>>(),
+            )
+            .filter(|charms| !charms.is_empty()),
+        })
+        .collect();
+
+    DenormalizedSpell {
+        version: norm_spell.version,
+        apps,
+        public_inputs: Some(public_inputs).filter(|m| !m.is_empty()),
+        ins,
+        refs: Some(refs).filter(|refs| !refs.is_empty()),
+        outs,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{NormalizedCharms, NormalizedTransaction, V3};
+    use charms_data::TxId;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn denormalize() {
+        let spell = NormalizedSpell {
+            version: V3,
+            tx: NormalizedTransaction {
+                ins: Some(vec![UtxoId(TxId([1; 32]), 0), UtxoId(TxId([1; 32]), 1)]),
+                refs: BTreeSet::new(),
+                outs: vec![
+                    NormalizedCharms::new(),
+                    NormalizedCharms::from([(1, Data::from(&5u64))]),
+                ],
+            },
+            app_public_inputs: BTreeMap::from([
+                (App::default(), Data::empty()),
+                (
+                    App {
+                        tag: 'n',
+                        ..Default::default()
+                    },
+                    Data::from(&"hello"),
+                ),
+            ]),
+        };
+
+        let denormalized = denormalized(&spell);
+        assert_eq!(denormalized.apps.len(), 2);
+        assert_eq!(
+            denormalized.public_inputs,
+            Some(BTreeMap::from([(
+                "$0001".to_string(),
+                Data::from(&"hello")
+            )]))
+        );
+        assert_eq!(denormalized.ins.len(), 2);
+        assert_eq!(denormalized.refs, None);
+        assert_eq!(denormalized.outs[0].charms, None);
+        assert_eq!(
+            denormalized.outs[1].charms,
+            Some(BTreeMap::from([("$0001".to_string(), Data::from(&5u64))]))
+        );
+    }
+}
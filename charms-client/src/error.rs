@@ -0,0 +1,60 @@
+use charms_data::{App, UtxoId};
+
+/// Reason a spell is rejected: it could not be extracted from a transaction, its proof could not
+/// be verified, or it is not well-formed.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SpellError {
+    /// The transaction does not have a spell: this is not an error for transactions that are not
+    /// supposed to carry one.
+    #[error("no spell in the transaction")]
+    NoSpell,
+    /// The transaction has no inputs (so no spell commitment input).
+    #[error("transaction does not have inputs")]
+    NoInputs,
+    /// The spell envelope (in the witness of the spell commitment input) is malformed.
+    #[error("malformed spell envelope: {0}")]
+    MalformedEnvelope(String),
+    /// The spell envelope is not in the last input of the transaction.
+    #[error("spell must be in the last input, found in input {0}")]
+    NotInLastInput(usize),
+    /// The spell and proof could not be parsed from the envelope data.
+    #[error("could not parse spell and proof: {0}")]
+    MalformedSpell(String),
+    /// The spell carries data it must inherit from the hosting transaction.
+    #[error("spell must inherit {0} from the enchanted tx")]
+    NotInherited(&'static str),
+    /// The spell has more outputs than the hosting transaction.
+    #[error("spell has {spell_outs} outputs, but the transaction has only {tx_outs}")]
+    TooManyOutputs { spell_outs: usize, tx_outs: usize },
+    /// The spell's protocol version is not supported.
+    #[error("unsupported spell version: {0}")]
+    UnsupportedVersion(u32),
+    /// The spell being proved is not of the current protocol version.
+    #[error("spell version {version} is not the current version {current}")]
+    VersionMismatch { version: u32, current: u32 },
+    /// The spell proof could not be verified.
+    #[error("could not verify spell proof: {0}")]
+    ProofInvalid(String),
+    /// A charm refers to an app index outside of the spell's app list.
+    #[error("charm app index {index} is out of range: the spell has {num_apps} apps")]
+    BadAppIndex { index: usize, num_apps: usize },
+    /// Transaction data the spell must have (after inheriting it from the hosting transaction)
+    /// is missing.
+    #[error("spell is missing tx.{0}")]
+    MissingTxData(&'static str),
+    /// An input or reference UTXO is not created by the previous transactions.
+    #[error("UTXO {0} is not created by prev transactions")]
+    NotCreatedByPrevTxs(UtxoId),
+    /// A previous transaction passed to the spell checker could not be decoded.
+    #[error("malformed prev transaction: {0}")]
+    MalformedPrevTx(String),
+    /// The previous transactions are not exactly the ones creating the spell inputs.
+    #[error("prev transactions do not match the spell input txids")]
+    PrevTxidsMismatch,
+    /// The number of app contract verification keys does not match the number of apps.
+    #[error("spell has {num_apps} apps, but {num_vks} app contract VKs are provided")]
+    AppsMismatch { num_apps: usize, num_vks: usize },
+    /// An app contract is not satisfied.
+    #[error("app contract verification failed for app {0}")]
+    AppContractFailed(App),
+}
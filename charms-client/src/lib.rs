@@ -1,10 +1,20 @@
-use crate::tx::extract_and_verify_spell;
+use crate::tx::{verify_spells_with, CompactPrevTx};
 use bitcoin::hashes::Hash;
 use charms_data::{App, Charms, Data, Transaction, TxId, UtxoId};
+pub use denormalized::{denormalized, DenormalizedInput, DenormalizedOutput, DenormalizedSpell};
+pub use error::SpellError;
+pub use lineage::{lineage, Lineage};
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, BTreeSet};
+pub use version::{PublicValuesEncoding, SpellFeatures, SpellVersion, SpellVersions};
 
+pub mod denormalized;
+pub mod error;
+pub mod lineage;
 pub mod tx;
+pub mod version;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
 /// Version `0` of the protocol.
 pub const V0: u32 = 0u32;
@@ -14,8 +24,15 @@ pub const V0_SPELL_VK: &str = "0x00e9398ac819e6dd281f81db3ada3fe5159c3cc40222b5d
 pub const V1_SPELL_VK: &str = "0x009f38f590ebca4c08c1e97b4064f39e4cd336eea4069669c5f5170a38a1ff97";
 /// Version `1` of the protocol.
 pub const V1: u32 = 1u32;
+/// Verification key for version `2` of the `charms-spell-checker` binary.
+pub const V2_SPELL_VK: &str = "0x00bd312b6026dbe4a2c16da1e8118d4fea31587a4b572b63155252d2daf69280";
 /// Version `2` of the protocol.
 pub const V2: u32 = 2u32;
+/// Version `3` of the protocol: the spell script may be a leaf of a larger Taproot tree (e.g.
+/// alongside a refund leaf), and the spell data may be compressed (see [`tx::SpellEncoding`]).
+/// Not current yet: it becomes current when the `charms-spell-checker` binary implementing it
+/// is released (with its verification key).
+pub const V3: u32 = 3u32;
 /// Current version of the protocol.
 pub const CURRENT_VERSION: u32 = V2;
 
@@ -52,6 +69,9 @@ impl NormalizedTransaction {
 /// Proof of correctness of a spell.
 pub type Proof = Box<[u8]>;
 
+/// Spells (if correct) of previous transactions and their numbers of outputs, by transaction ID.
+pub type PrevSpells = BTreeMap<TxId, (Option<NormalizedSpell>, usize)>;
+
 /// Normalized representation of a spell.
 /// Can be committed as public input.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -65,71 +85,101 @@ pub struct NormalizedSpell {
 }
 
 /// Extract spells from previous transactions.
-#[tracing::instrument(level = "debug", skip(prev_txs, spell_vk))]
-pub fn prev_spells(
-    prev_txs: &Vec<bitcoin::Transaction>,
-    spell_vk: &str,
-) -> BTreeMap<TxId, (Option<NormalizedSpell>, usize)> {
-    prev_txs
+/// Also returns the number of outputs of each previous transaction.
+/// Transactions without a correct spell map to `None`: their outputs carry no charms.
+pub fn prev_spells(prev_txs: &[bitcoin::Transaction], spell_vk: &str) -> PrevSpells {
+    prev_spells_with(prev_txs, &SpellVersions::cached(spell_vk))
+}
+
+/// Extract spells from previous transactions, accepting only the spell versions in `versions`.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn prev_spells_with(prev_txs: &[bitcoin::Transaction], versions: &SpellVersions) -> PrevSpells {
+    let tx_ids = prev_txs
         .iter()
-        .map(|tx| {
-            let tx_id = TxId(tx.compute_txid().to_byte_array());
-            (
-                tx_id,
-                (
-                    extract_and_verify_spell(tx, spell_vk)
-                        .map_err(|e| {
-                            tracing::info!("no correct spell in tx {}: {}", tx_id, e);
-                        })
-                        .ok(),
-                    tx.output.len(),
-                ),
-            )
+        .map(|tx| TxId(tx.compute_txid().to_byte_array()));
+    prev_spells_by_txid(tx_ids, prev_txs, versions)
+}
+
+/// Extract spells from previous transactions in compact form (see [`tx::CompactPrevTx`]),
+/// accepting only the spell versions in `versions`.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn compact_prev_spells(
+    prev_txs: &[CompactPrevTx],
+    versions: &SpellVersions,
+) -> Result<PrevSpells, SpellError> {
+    let (tx_ids, txs): (Vec<_>, Vec<_>) = prev_txs
+        .iter()
+        .map(CompactPrevTx::decode)
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .unzip();
+    Ok(prev_spells_by_txid(tx_ids, &txs, versions))
+}
+
+fn prev_spells_by_txid(
+    tx_ids: impl IntoIterator<Item = TxId>,
+    prev_txs: &[bitcoin::Transaction],
+    versions: &SpellVersions,
+) -> PrevSpells {
+    tx_ids
+        .into_iter()
+        .zip(prev_txs)
+        .zip(verify_spells_with(prev_txs, versions))
+        .map(|((tx_id, tx), spell_result)| {
+            let spell_opt = match spell_result {
+                Ok(spell) => Some(spell),
+                Err(SpellError::NoSpell) => None,
+                Err(e) => {
+                    tracing::warn!("incorrect spell in tx {}: {}", tx_id, e);
+                    None
+                }
+            };
+            (tx_id, (spell_opt, tx.output.len()))
         })
         .collect()
 }
 
 /// Check if the spell is well-formed.
+/// Prints the reason to stderr if it is not: use [`check_well_formed`] to get it as a
+/// [`SpellError`].
 #[tracing::instrument(level = "debug", skip(spell, prev_spells))]
-pub fn well_formed(
+pub fn well_formed(spell: &NormalizedSpell, prev_spells: &PrevSpells) -> bool {
+    check_well_formed(spell, prev_spells)
+        .map_err(|e| eprintln!("{}", e))
+        .is_ok()
+}
+
+/// Check if the spell is well-formed (according to the rules of its version). Returns the reason
+/// if it is not.
+#[tracing::instrument(level = "debug", skip(spell, prev_spells))]
+pub fn check_well_formed(
     spell: &NormalizedSpell,
-    prev_spells: &BTreeMap<TxId, (Option<NormalizedSpell>, usize)>,
-) -> bool {
-    if spell.version != CURRENT_VERSION {
-        eprintln!(
-            "spell version {} is not the current version {}",
-            spell.version, CURRENT_VERSION
-        );
-        return false;
-    }
-    let created_by_prev_spells = |utxo_id: &UtxoId| -> bool {
-        prev_spells
-            .get(&utxo_id.0)
-            .and_then(|(_, num_tx_outs)| Some(utxo_id.1 as usize <= *num_tx_outs))
-            == Some(true)
+    prev_spells: &PrevSpells,
+) -> Result<(), SpellError> {
+    let created_by_prev_spells = |utxo_id: &UtxoId| -> Result<(), SpellError> {
+        match prev_spells.get(&utxo_id.0) {
+            Some((_, num_tx_outs)) if (utxo_id.1 as usize) < *num_tx_outs => Ok(()),
+            _ => Err(SpellError::NotCreatedByPrevTxs(utxo_id.clone())),
+        }
     };
-    if !spell
+    let num_apps = spell.app_public_inputs.len();
+    if let Some(&index) = spell
         .tx
         .outs
         .iter()
-        .all(|n_charm| n_charm.keys().all(|i| i < &spell.app_public_inputs.len()))
+        .flat_map(|n_charm| n_charm.keys())
+        .find(|&&i| i >= num_apps)
     {
-        eprintln!("charm app index higher than app_public_inputs.len()");
-        return false;
+        return Err(SpellError::BadAppIndex { index, num_apps });
     }
     // check that UTXOs we're spending or referencing in this tx
     // are created by pre-req transactions
     let Some(tx_ins) = &spell.tx.ins else {
-        eprintln!("no tx.ins");
-        return false;
+        return Err(SpellError::MissingTxData("ins"));
     };
-    if !tx_ins.iter().all(created_by_prev_spells)
-        || !spell.tx.refs.iter().all(created_by_prev_spells)
-    {
-        eprintln!("input or reference UTXOs are not created by prev transactions");
-        return false;
-    }
-    true
+    tx_ins.iter().try_for_each(created_by_prev_spells)?;
+    spell.tx.refs.iter().try_for_each(created_by_prev_spells)?;
+    Ok(())
 }
 
 /// Return the list of apps in the spell.
@@ -138,10 +188,8 @@ pub fn apps(spell: &NormalizedSpell) -> Vec<App> {
 }
 
 /// Convert normalized spell to [`charms_data::Transaction`].
-pub fn to_tx(
-    spell: &NormalizedSpell,
-    prev_spells: &BTreeMap<TxId, (Option<NormalizedSpell>, usize)>,
-) -> Transaction {
+/// The spell must be well-formed (see [`check_well_formed`]).
+pub fn to_tx(spell: &NormalizedSpell, prev_spells: &PrevSpells) -> Transaction {
     let from_utxo_id = |utxo_id: &UtxoId| -> (UtxoId, Charms) {
         let (prev_spell_opt, _) = &prev_spells[&utxo_id.0];
         let charms = prev_spell_opt
@@ -170,6 +218,20 @@ pub fn to_tx(
     }
 }
 
+/// Check the spell is well-formed and convert it to [`charms_data::Transaction`], accepting only
+/// the spell versions in `versions`. `prev_txs` are the transactions creating the spell's inputs
+/// and references.
+pub fn checked_to_tx(
+    spell: &NormalizedSpell,
+    prev_txs: &[bitcoin::Transaction],
+    versions: &SpellVersions,
+) -> Result<Transaction, SpellError> {
+    versions.get(spell.version)?;
+    let prev_spells = prev_spells_with(prev_txs, versions);
+    check_well_formed(spell, &prev_spells)?;
+    Ok(to_tx(spell, &prev_spells))
+}
+
 /// Return [`charms_data::Charms`] for the given [`NormalizedCharms`].
 pub fn charms(spell: &NormalizedSpell, n_charms: &NormalizedCharms) -> Charms {
     let apps = apps(spell);
@@ -182,7 +244,13 @@ pub fn charms(spell: &NormalizedSpell, n_charms: &NormalizedCharms) -> Charms {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct SpellProverInput {
     pub self_spell_vk: String,
+    /// prev transactions, stripped of the witness data not needed by the spell checker (see
+    /// [`tx::strip_prev_tx`]). Empty if the spell's version takes `compact_prev_txs` instead.
     pub prev_txs: Vec<bitcoin::Transaction>,
+    /// prev transactions in compact form, for spell versions with
+    /// [`SpellFeatures`]`.compact_prev_txs`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub compact_prev_txs: Vec<CompactPrevTx>,
     pub spell: NormalizedSpell,
     /// indices of apps in the spell that have contract proofs
     pub app_contract_proofs: BTreeSet<usize>, // proofs are provided in input stream data
@@ -190,6 +258,534 @@ pub struct SpellProverInput {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::tx::extract_and_verify_spell;
+    use bitcoin::{absolute::LockTime, transaction::Version, OutPoint, TxIn};
+    use proptest::prelude::*;
+    use test_strategy::proptest;
+
+    type PrevTx = ([u8; 32], bool, Vec<Option<u64>>);
+
+    /// Previous transactions: txid, whether the tx has a spell, and optional charm amounts of
+    /// its outputs.
+    fn prev_txs() -> impl Strategy<Value = Vec<PrevTx>> {
+        prop::collection::vec(
+            (
+                any::<[u8; 32]>(),
+                any::<bool>(),
+                prop::collection::vec(prop::option::of(any::<u64>()), 0..4),
+            ),
+            1..4,
+        )
+    }
+
+    fn to_prev_spells(prev_txs: &[PrevTx]) -> PrevSpells {
+        prev_txs
+            .iter()
+            .map(|(txid, has_spell, amounts)| {
+                let prev_spell = has_spell.then(|| NormalizedSpell {
+                    version: CURRENT_VERSION,
+                    tx: NormalizedTransaction {
+                        ins: Some(vec![]),
+                        refs: BTreeSet::new(),
+                        outs: amounts
+                            .iter()
+                            .map(|amount| match amount {
+                                Some(amount) => NormalizedCharms::from([(0, Data::from(amount))]),
+                                None => NormalizedCharms::new(),
+                            })
+                            .collect(),
+                    },
+                    app_public_inputs: BTreeMap::from([(App::default(), Data::empty())]),
+                });
+                (TxId(*txid), (prev_spell, amounts.len()))
+            })
+            .collect()
+    }
+
+    fn spell_spending(ins: Vec<UtxoId>, refs: BTreeSet<UtxoId>) -> NormalizedSpell {
+        NormalizedSpell {
+            version: V3,
+            tx: NormalizedTransaction {
+                ins: Some(ins),
+                refs,
+                outs: vec![],
+            },
+            app_public_inputs: BTreeMap::new(),
+        }
+    }
+
+    /// Arbitrary spell and its prev spells. Input and reference UTXOs may point to unknown
+    /// transactions (index == number of prev txs) or to out-of-range outputs, may repeat and may
+    /// overlap.
+    fn spell_and_prev_spells() -> impl Strategy<Value = (NormalizedSpell, PrevSpells)> {
+        prev_txs()
+            .prop_flat_map(|prev_txs| {
+                let utxo = (0..=prev_txs.len(), 0u32..5);
+                (
+                    Just(prev_txs),
+                    prop::collection::vec(utxo.clone(), 0..5),
+                    prop::collection::vec(utxo, 0..4),
+                )
+            })
+            .prop_map(|(prev_txs, ins, refs)| {
+                let utxo_id = |&(i, vout): &(usize, u32)| {
+                    let txid = prev_txs
+                        .get(i)
+                        .map(|(txid, ..)| TxId(*txid))
+                        .unwrap_or(TxId([0xff; 32]));
+                    UtxoId(txid, vout)
+                };
+                let spell = spell_spending(
+                    ins.iter().map(utxo_id).collect(),
+                    refs.iter().map(utxo_id).collect(),
+                );
+                (spell, to_prev_spells(&prev_txs))
+            })
+    }
+
+    /// Spell spending and referencing only outputs of its prev transactions.
+    fn well_formed_spell_and_prev_spells() -> impl Strategy<Value = (NormalizedSpell, PrevSpells)> {
+        prev_txs()
+            .prop_flat_map(|prev_txs| {
+                let utxo = (0..prev_txs.len(), any::<u32>());
+                (
+                    Just(prev_txs),
+                    prop::collection::btree_set(utxo.clone(), 0..5),
+                    prop::collection::btree_set(utxo, 0..4),
+                )
+            })
+            .prop_map(|(prev_txs, ins, refs)| {
+                let utxo_id = |&(i, n): &(usize, u32)| {
+                    let (txid, _, amounts) = &prev_txs[i];
+                    let num_outs = amounts.len() as u32;
+                    (num_outs > 0).then(|| UtxoId(TxId(*txid), n % num_outs))
+                };
+                let ins: BTreeSet<UtxoId> = ins.iter().filter_map(utxo_id).collect();
+                let refs = refs
+                    .iter()
+                    .filter_map(utxo_id)
+                    .filter(|utxo_id| !ins.contains(utxo_id))
+                    .collect();
+                let spell = spell_spending(ins.into_iter().collect(), refs);
+                (spell, to_prev_spells(&prev_txs))
+            })
+    }
+
+    #[proptest]
+    fn well_formed_iff_utxos_are_valid(
+        #[strategy(spell_and_prev_spells())] input: (NormalizedSpell, PrevSpells),
+    ) {
+        let (spell, prev_spells) = input;
+        let ins = spell.tx.ins.as_ref().unwrap();
+        let created_by_prev_txs = |utxo_id: &UtxoId| {
+            prev_spells
+                .get(&utxo_id.0)
+                .is_some_and(|(_, num_tx_outs)| (utxo_id.1 as usize) < *num_tx_outs)
+        };
+        let expected =
+            ins.iter().all(created_by_prev_txs) && spell.tx.refs.iter().all(created_by_prev_txs);
+
+        prop_assert_eq!(check_well_formed(&spell, &prev_spells).is_ok(), expected);
+    }
+
+    #[proptest]
+    fn to_tx_of_well_formed_spell(
+        #[strategy(well_formed_spell_and_prev_spells())] input: (NormalizedSpell, PrevSpells),
+    ) {
+        let (spell, prev_spells) = input;
+        prop_assert_eq!(check_well_formed(&spell, &prev_spells), Ok(()));
+
+        let tx = to_tx(&spell, &prev_spells);
+        let ins = spell.tx.ins.as_ref().unwrap();
+        prop_assert_eq!(tx.ins.keys().collect::<BTreeSet<_>>(), ins.iter().collect());
+        prop_assert_eq!(
+            tx.refs.keys().collect::<BTreeSet<_>>(),
+            spell.tx.refs.iter().collect()
+        );
+
+        for (utxo_id, tx_charms) in tx.ins.iter().chain(tx.refs.iter()) {
+            let (prev_spell, _) = &prev_spells[&utxo_id.0];
+            let expected = prev_spell
+                .as_ref()
+                .and_then(|prev_spell| {
+                    prev_spell
+                        .tx
+                        .outs
+                        .get(utxo_id.1 as usize)
+                        .map(|n_charms| charms(prev_spell, n_charms))
+                })
+                .unwrap_or_default();
+            prop_assert_eq!(tx_charms, &expected);
+        }
+    }
+
+    #[proptest]
+    fn duplicated_refs_collapse(
+        #[strategy(well_formed_spell_and_prev_spells())] input: (NormalizedSpell, PrevSpells),
+    ) {
+        let (spell, prev_spells) = input;
+        prop_assume!(!spell.tx.refs.is_empty());
+
+        // encode the refs with each one repeated
+        let mut value = ciborium::Value::serialized(&spell).unwrap();
+        let ciborium::Value::Map(fields) = &mut value else {
+            unreachable!()
+        };
+        let (_, ciborium::Value::Map(tx_fields)) = fields
+            .iter_mut()
+            .find(|(k, _)| k.as_text() == Some("tx"))
+            .unwrap()
+        else {
+            unreachable!()
+        };
+        let (_, ciborium::Value::Array(refs)) = tx_fields
+            .iter_mut()
+            .find(|(k, _)| k.as_text() == Some("refs"))
+            .unwrap()
+        else {
+            unreachable!()
+        };
+        let duplicated: Vec<_> = refs.iter().flat_map(|r| [r.clone(), r.clone()]).collect();
+        *refs = duplicated;
+
+        let spell2: NormalizedSpell = value.deserialized().unwrap();
+        prop_assert_eq!(&spell2, &spell);
+        prop_assert_eq!(check_well_formed(&spell2, &prev_spells), Ok(()));
+    }
+
+    #[test]
+    fn vout_out_of_range() {
+        let prev_spells = to_prev_spells(&[([1; 32], true, vec![Some(1), None])]);
+        let spell = spell_spending(vec![UtxoId(TxId([1; 32]), 1)], BTreeSet::new());
+        assert_eq!(check_well_formed(&spell, &prev_spells), Ok(()));
+
+        let utxo_id = UtxoId(TxId([1; 32]), 2);
+        let spell = spell_spending(vec![utxo_id.clone()], BTreeSet::new());
+        assert_eq!(
+            check_well_formed(&spell, &prev_spells),
+            Err(SpellError::NotCreatedByPrevTxs(utxo_id))
+        );
+    }
+
+    #[test]
+    fn extract_spell_errors() {
+        let mut tx = bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        };
+        assert_eq!(
+            extract_and_verify_spell(&tx, "").unwrap_err(),
+            SpellError::NoInputs
+        );
+
+        tx.input.push(TxIn {
+            previous_output: OutPoint::null(),
+            ..Default::default()
+        });
+        assert_eq!(
+            extract_and_verify_spell(&tx, "").unwrap_err(),
+            SpellError::NoSpell
+        );
+        let prev_spells = prev_spells(&[tx.clone()], "");
+        assert_eq!(prev_spells.len(), 1);
+        assert!(prev_spells.values().all(|(spell, _)| spell.is_none()));
+    }
+
+    /// Spell commitment input. The spell script is a leaf of a Taproot tree with `num_leaves`
+    /// leaves (1 or 2).
+    fn spell_tx_in(spell: &NormalizedSpell, proof: &[u8], num_leaves: usize) -> TxIn {
+        let spell_data = charms_data::util::write(&(spell, proof)).unwrap();
+        envelope_tx_in(&[&spell_data], num_leaves)
+    }
+
+    /// Spell commitment input with an envelope of `pushes` (following `b"spell"`).
+    fn envelope_tx_in(pushes: &[&[u8]], num_leaves: usize) -> TxIn {
+        let mut builder = bitcoin::script::Builder::new()
+            .push_opcode(bitcoin::opcodes::OP_FALSE)
+            .push_opcode(bitcoin::opcodes::all::OP_IF)
+            .push_slice(b"spell");
+        for push in pushes {
+            builder = builder.push_slice(<&bitcoin::script::PushBytes>::try_from(*push).unwrap());
+        }
+        let script = builder
+            .push_opcode(bitcoin::opcodes::all::OP_ENDIF)
+            .into_script();
+        // leaf version byte, then the generator point as the internal key, then the merkle branch
+        let mut control_block = <Vec<u8> as bitcoin::hex::FromHex>::from_hex(
+            "c079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        control_block.extend(vec![0u8; 32 * (num_leaves - 1)]);
+        TxIn {
+            previous_output: OutPoint::null(),
+            witness: bitcoin::Witness::from_slice(&[script.as_bytes(), &control_block]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn find_and_verify_spell() {
+        let spell = NormalizedSpell {
+            version: CURRENT_VERSION,
+            tx: NormalizedTransaction {
+                ins: None,
+                refs: BTreeSet::new(),
+                outs: vec![],
+            },
+            app_public_inputs: BTreeMap::new(),
+        };
+        let mut tx = bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![spell_tx_in(&spell, b"proof", 1), TxIn::default()],
+            output: vec![],
+        };
+        let versions = SpellVersions::new("");
+
+        // the spell is found in any input, but must be in the last one to be correct
+        let (found_spell, proof, input_index) = tx::find_spell(&tx).unwrap().unwrap();
+        assert_eq!(
+            (&found_spell, &*proof, input_index),
+            (&spell, &b"proof"[..], 0)
+        );
+        assert_eq!(
+            tx::verify_spell_proof(&tx, found_spell, &proof, input_index, &versions),
+            Err(SpellError::NotInLastInput(0))
+        );
+
+        tx.input.reverse();
+        let (found_spell, proof, input_index) = tx::find_spell(&tx).unwrap().unwrap();
+        assert_eq!(input_index, 1);
+        assert!(matches!(
+            tx::verify_spell_proof(&tx, found_spell, &proof, input_index, &versions),
+            Err(SpellError::ProofInvalid(_))
+        ));
+        assert!(matches!(
+            extract_and_verify_spell(&tx, ""),
+            Err(SpellError::ProofInvalid(_))
+        ));
+
+        // a malformed envelope is not skipped over
+        tx.input.push(envelope_tx_in(&[b"not a spell"], 1));
+        assert!(matches!(
+            tx::find_spell(&tx),
+            Err(SpellError::MalformedSpell(_))
+        ));
+
+        tx.input.truncate(1);
+        assert_eq!(tx::find_spell(&tx), Ok(None));
+    }
+
     #[test]
-    fn dummy() {}
+    fn multi_leaf_taproot_tree() {
+        let mut spell = NormalizedSpell {
+            version: V3,
+            tx: NormalizedTransaction {
+                ins: None,
+                refs: BTreeSet::new(),
+                outs: vec![],
+            },
+            app_public_inputs: BTreeMap::new(),
+        };
+        let (parsed_spell, _) =
+            tx::parse_spell_and_proof(&spell_tx_in(&spell, b"proof", 2)).unwrap();
+        assert_eq!(parsed_spell, spell);
+
+        spell.version = V2;
+        assert!(tx::parse_spell_and_proof(&spell_tx_in(&spell, b"proof", 1)).is_ok());
+        assert!(matches!(
+            tx::parse_spell_and_proof(&spell_tx_in(&spell, b"proof", 2)),
+            Err(SpellError::MalformedEnvelope(_))
+        ));
+    }
+
+    #[test]
+    fn compressed_spell_data() {
+        let mut spell = NormalizedSpell {
+            version: V3,
+            tx: NormalizedTransaction {
+                ins: None,
+                refs: BTreeSet::new(),
+                outs: vec![NormalizedCharms::from([(0, Data::from(&"a".repeat(1000)))])],
+            },
+            app_public_inputs: BTreeMap::from([(App::default(), Data::empty())]),
+        };
+        let proof = b"proof";
+
+        let (encoding, spell_data) = tx::encode_spell_data(&spell, proof);
+        assert_eq!(encoding, tx::SpellEncoding::Deflate);
+        let marker = [encoding.marker().unwrap()];
+        let tx_in = envelope_tx_in(&[&marker, &spell_data], 1);
+        let (parsed_spell, parsed_proof) = tx::parse_spell_and_proof(&tx_in).unwrap();
+        assert_eq!(parsed_spell, spell);
+        assert_eq!(parsed_proof.as_ref(), proof);
+
+        let tx_in = envelope_tx_in(&[&[42], &spell_data], 1);
+        assert!(matches!(
+            tx::parse_spell_and_proof(&tx_in),
+            Err(SpellError::MalformedEnvelope(_))
+        ));
+
+        // small spells are not worth compressing
+        spell.tx.outs = vec![];
+        spell.app_public_inputs = BTreeMap::new();
+        assert_eq!(
+            tx::encode_spell_data(&spell, proof).0,
+            tx::SpellEncoding::Cbor
+        );
+
+        // earlier versions don't support compression
+        spell.version = V2;
+        let spell_data =
+            tx::SpellEncoding::Deflate.encode(&charms_data::util::write(&(&spell, proof)).unwrap());
+        let tx_in = envelope_tx_in(&[&marker, &spell_data], 1);
+        assert!(matches!(
+            tx::parse_spell_and_proof(&tx_in),
+            Err(SpellError::MalformedEnvelope(_))
+        ));
+    }
+
+    #[test]
+    fn strip_prev_tx() {
+        let spell = NormalizedSpell {
+            version: CURRENT_VERSION,
+            tx: NormalizedTransaction {
+                ins: None,
+                refs: BTreeSet::new(),
+                outs: vec![],
+            },
+            app_public_inputs: BTreeMap::new(),
+        };
+        let funding_tx_in = TxIn {
+            witness: bitcoin::Witness::from_slice(&[[1u8; 64]]),
+            ..Default::default()
+        };
+        let tx = bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![funding_tx_in.clone(), spell_tx_in(&spell, b"proof", 1)],
+            output: vec![],
+        };
+
+        let stripped = tx::strip_prev_tx(&tx);
+        assert_eq!(stripped.compute_txid(), tx.compute_txid());
+        assert!(stripped.input[0].witness.is_empty());
+        assert_eq!(stripped.input[1].witness, tx.input[1].witness);
+        assert_eq!(
+            tx::find_spell(&stripped).map(|found| found.map(|(s, ..)| s)),
+            Ok(Some(spell))
+        );
+
+        // no spell: no witness is needed
+        let tx = bitcoin::Transaction {
+            input: vec![funding_tx_in],
+            ..tx
+        };
+        assert!(tx::strip_prev_tx(&tx).input[0].witness.is_empty());
+    }
+
+    #[test]
+    fn compact_prev_tx() {
+        let spell = NormalizedSpell {
+            version: CURRENT_VERSION,
+            tx: NormalizedTransaction {
+                ins: None,
+                refs: BTreeSet::new(),
+                outs: vec![],
+            },
+            app_public_inputs: BTreeMap::new(),
+        };
+        let tx = bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![
+                TxIn {
+                    witness: bitcoin::Witness::from_slice(&[[1u8; 64]]),
+                    ..Default::default()
+                },
+                spell_tx_in(&spell, b"proof", 1),
+            ],
+            output: vec![bitcoin::TxOut {
+                value: bitcoin::Amount::from_sat(1000),
+                script_pubkey: bitcoin::ScriptBuf::new(),
+            }],
+        };
+
+        let compact = tx::CompactPrevTx::new(&tx);
+        assert_eq!(compact.spell_witness.as_ref(), Some(&tx.input[1].witness));
+        let compact: tx::CompactPrevTx =
+            charms_data::util::read(charms_data::util::write(&compact).unwrap().as_slice())
+                .unwrap();
+
+        let (tx_id, decoded) = compact.decode().unwrap();
+        assert_eq!(tx_id, TxId(tx.compute_txid().to_byte_array()));
+        assert_eq!(decoded, tx::strip_prev_tx(&tx));
+        assert_eq!(
+            compact_prev_spells(&[compact], &SpellVersions::cached(V2_SPELL_VK))
+                .unwrap()
+                .get(&tx_id)
+                .map(|(_, num_tx_outs)| *num_tx_outs),
+            Some(tx.output.len())
+        );
+
+        // the txid is the hash of the serialization without witness data
+        let with_witness = tx::CompactPrevTx {
+            tx: bitcoin::consensus::serialize(&tx),
+            spell_witness: None,
+        };
+        assert!(matches!(
+            with_witness.decode(),
+            Err(SpellError::MalformedPrevTx(_))
+        ));
+        let garbage = tx::CompactPrevTx {
+            tx: vec![1, 2, 3],
+            spell_witness: None,
+        };
+        assert!(matches!(
+            garbage.decode(),
+            Err(SpellError::MalformedPrevTx(_))
+        ));
+    }
+
+    #[test]
+    fn well_formed_errors() {
+        let mut spell = NormalizedSpell {
+            version: V3,
+            tx: NormalizedTransaction {
+                ins: Some(vec![]),
+                refs: BTreeSet::new(),
+                outs: vec![NormalizedCharms::from([(1, Data::empty())])],
+            },
+            app_public_inputs: BTreeMap::from([(App::default(), Data::empty())]),
+        };
+        let prev_spells = BTreeMap::new();
+        assert_eq!(
+            check_well_formed(&spell, &prev_spells),
+            Err(SpellError::BadAppIndex {
+                index: 1,
+                num_apps: 1
+            })
+        );
+
+        spell.tx.outs = vec![NormalizedCharms::from([(0, Data::empty())])];
+        assert_eq!(check_well_formed(&spell, &prev_spells), Ok(()));
+
+        let utxo_id = UtxoId(TxId([1; 32]), 0);
+        spell.tx.refs.insert(utxo_id.clone());
+        assert_eq!(
+            check_well_formed(&spell, &prev_spells),
+            Err(SpellError::NotCreatedByPrevTxs(utxo_id))
+        );
+        assert!(!well_formed(&spell, &prev_spells));
+
+        spell.tx.refs.clear();
+        spell.tx.ins = None;
+        assert_eq!(
+            check_well_formed(&spell, &prev_spells),
+            Err(SpellError::MissingTxData("ins"))
+        );
+    }
 }
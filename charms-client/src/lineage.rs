@@ -0,0 +1,212 @@
+use crate::{to_tx, tx::extract_and_verify_spell_with, NormalizedSpell, SpellError, SpellVersions};
+use charms_data::{App, Charms, TxId, UtxoId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// Spell (if any) and number of outputs of a transaction.
+type TxSpell = (Option<NormalizedSpell>, usize);
+
+/// Provenance of the charms in a UTXO: a DAG of the spell transactions the charms came through.
+///
+/// Transactions are the nodes and charmed UTXOs are the edges: each input in
+/// [`Transition::ins`] is an output of another transaction in [`Lineage::txs`], unless the trace
+/// was truncated at that transition.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Lineage {
+    /// The traced UTXO.
+    pub utxo: UtxoId,
+    /// Charms in the traced UTXO.
+    pub charms: Charms,
+    /// Spell transactions the charms came through, keyed by transaction ID.
+    pub txs: BTreeMap<TxId, Transition>,
+}
+
+/// Charm transition in a spell transaction.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Transition {
+    /// Depth of the transaction: `0` for the transaction creating the traced UTXO.
+    pub depth: usize,
+    /// Spent UTXOs carrying charms.
+    pub ins: BTreeMap<UtxoId, Charms>,
+    /// Output charms.
+    pub outs: Vec<Charms>,
+    /// Apps with charms in the outputs but not in the inputs: the transaction mints their charms.
+    pub minted: BTreeSet<App>,
+    /// The inputs have not been traced further (the depth limit is reached).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+/// Trace the provenance of the charms in `utxo`, walking backwards through spells up to the
+/// transactions minting them.
+///
+/// `fetch_tx` is the transaction source. At most `max_depth` transactions are walked back from
+/// the transaction creating `utxo` (`None` means no limit). Transactions without a correct
+/// spell carry no charms and end the walk. Only spells of versions in `versions` are accepted.
+#[tracing::instrument(level = "debug", skip(versions, fetch_tx))]
+pub fn lineage<E>(
+    utxo: &UtxoId,
+    max_depth: Option<usize>,
+    versions: &SpellVersions,
+    mut fetch_tx: impl FnMut(&TxId) -> Result<bitcoin::Transaction, E>,
+) -> Result<Lineage, E> {
+    trace(utxo, max_depth, |txid| {
+        let tx = fetch_tx(txid)?;
+        let spell_opt = match extract_and_verify_spell_with(&tx, versions) {
+            Ok(spell) => Some(spell),
+            Err(SpellError::NoSpell) => None,
+            Err(e) => {
+                tracing::warn!("incorrect spell in tx {}: {}", txid, e);
+                None
+            }
+        };
+        Ok((spell_opt, tx.output.len()))
+    })
+}
+
+/// Walk backwards from `utxo` through the (verified) spells returned by `get_spell`.
+fn trace<E>(
+    utxo: &UtxoId,
+    max_depth: Option<usize>,
+    mut get_spell: impl FnMut(&TxId) -> Result<TxSpell, E>,
+) -> Result<Lineage, E> {
+    let mut spells: BTreeMap<TxId, TxSpell> = BTreeMap::new();
+    let mut fetch_spell = |txid: &TxId| -> Result<TxSpell, E> {
+        if let Some(spell) = spells.get(txid) {
+            return Ok(spell.clone());
+        }
+        let spell = get_spell(txid)?;
+        spells.insert(*txid, spell.clone());
+        Ok(spell)
+    };
+
+    let mut lineage = Lineage {
+        utxo: utxo.clone(),
+        ..Default::default()
+    };
+    let mut queue = VecDeque::from([(utxo.0, 0)]);
+
+    while let Some((txid, depth)) = queue.pop_front() {
+        if lineage.txs.contains_key(&txid) {
+            continue;
+        }
+        let (Some(spell), _) = fetch_spell(&txid)? else {
+            continue;
+        };
+
+        let prev_txids: BTreeSet<&TxId> = spell
+            .tx
+            .ins
+            .iter()
+            .flatten()
+            .chain(spell.tx.refs.iter())
+            .map(|utxo_id| &utxo_id.0)
+            .collect();
+        let prev_spells = prev_txids
+            .into_iter()
+            .map(|prev_txid| Ok((*prev_txid, fetch_spell(prev_txid)?)))
+            .collect::<Result<_, E>>()?;
+        let tx = to_tx(&spell, &prev_spells);
+
+        let ins: BTreeMap<UtxoId, Charms> = tx
+            .ins
+            .into_iter()
+            .filter(|(_, charms)| !charms.is_empty())
+            .collect();
+        let in_apps: BTreeSet<&App> = ins.values().flat_map(|charms| charms.keys()).collect();
+        let minted = tx
+            .outs
+            .iter()
+            .flat_map(|charms| charms.keys())
+            .filter(|app| !in_apps.contains(app))
+            .cloned()
+            .collect();
+
+        let truncated = !ins.is_empty() && max_depth.is_some_and(|max_depth| depth >= max_depth);
+        if !truncated {
+            queue.extend(ins.keys().map(|utxo_id| (utxo_id.0, depth + 1)));
+        }
+
+        if depth == 0 {
+            lineage.charms = tx.outs.get(utxo.1 as usize).cloned().unwrap_or_default();
+        }
+        lineage.txs.insert(
+            txid,
+            Transition {
+                depth,
+                ins,
+                outs: tx.outs,
+                minted,
+                truncated,
+            },
+        );
+    }
+
+    Ok(lineage)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{NormalizedCharms, NormalizedTransaction, CURRENT_VERSION};
+    use charms_data::{Data, TOKEN};
+    use std::convert::Infallible;
+
+    fn token() -> App {
+        App {
+            tag: TOKEN,
+            ..Default::default()
+        }
+    }
+
+    fn spell(ins: Vec<UtxoId>, amounts: &[u64]) -> TxSpell {
+        let spell = NormalizedSpell {
+            version: CURRENT_VERSION,
+            tx: NormalizedTransaction {
+                ins: Some(ins),
+                refs: BTreeSet::new(),
+                outs: amounts
+                    .iter()
+                    .map(|amount| NormalizedCharms::from([(0, Data::from(amount))]))
+                    .collect(),
+            },
+            app_public_inputs: BTreeMap::from([(token(), Data::empty())]),
+        };
+        (Some(spell), amounts.len())
+    }
+
+    #[test]
+    fn trace_charms() {
+        let [z, a, b, c] = [0u8, 1, 2, 3].map(|i| TxId([i; 32]));
+        let spells = BTreeMap::from([
+            // mint in `a`, spending an output of `z` (no spell)
+            (a, spell(vec![UtxoId(z, 0)], &[100])),
+            (b, spell(vec![UtxoId(a, 0)], &[60, 40])),
+            (c, spell(vec![UtxoId(b, 0), UtxoId(b, 1)], &[100])),
+        ]);
+        let get_spell = |txid: &TxId| -> Result<TxSpell, Infallible> {
+            Ok(spells.get(txid).cloned().unwrap_or((None, 0)))
+        };
+
+        let lineage = trace(&UtxoId(c, 0), None, get_spell).unwrap();
+        assert_eq!(
+            lineage.charms,
+            Charms::from([(token(), Data::from(&100u64))])
+        );
+        assert_eq!(lineage.txs.keys().collect::<Vec<_>>(), vec![&a, &b, &c]);
+        assert_eq!(lineage.txs[&a].depth, 2);
+        assert_eq!(lineage.txs[&a].minted, BTreeSet::from([token()]));
+        assert!(lineage.txs[&a].ins.is_empty());
+        assert_eq!(lineage.txs[&c].ins.len(), 2);
+        assert!(lineage.txs[&c].minted.is_empty());
+        assert!(lineage.txs.values().all(|transition| !transition.truncated));
+
+        let lineage = trace(&UtxoId(c, 0), Some(1), get_spell).unwrap();
+        assert_eq!(lineage.txs.keys().collect::<Vec<_>>(), vec![&b, &c]);
+        assert!(lineage.txs[&b].truncated);
+
+        let lineage = trace(&UtxoId(z, 0), None, get_spell).unwrap();
+        assert!(lineage.charms.is_empty());
+        assert!(lineage.txs.is_empty());
+    }
+}
@@ -1,48 +1,138 @@
-use crate::{NormalizedSpell, Proof, CURRENT_VERSION, V0, V0_SPELL_VK, V1, V1_SPELL_VK};
-use anyhow::{anyhow, bail, ensure};
+use crate::{NormalizedSpell, Proof, SpellError, SpellFeatures, SpellVersions};
 use bitcoin::{
-    hashes::{serde::Serialize, Hash},
+    hashes::{sha256d, Hash},
     opcodes::all::{OP_ENDIF, OP_IF},
-    script::{Instruction, PushBytes},
-    TxIn,
+    script::{Instruction, Instructions, PushBytes},
+    taproot::ControlBlock,
+    TxIn, Witness,
 };
 use charms_data::{util, TxId, UtxoId};
-use sp1_primitives::io::SP1PublicValues;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, Bytes};
 use sp1_verifier::Groth16Verifier;
 
 /// Extract a [`NormalizedSpell`] from a transaction and verify it.
-/// Incorrect spells are rejected.
-#[tracing::instrument(level = "debug", skip_all)]
+/// Incorrect spells are rejected: the [`SpellError`] says why.
+/// Transactions without a spell are rejected with [`SpellError::NoSpell`].
+/// Accepts all spell versions known to this crate: `spell_vk` is the verification key of the
+/// current version of the spell checker.
 pub fn extract_and_verify_spell(
     tx: &bitcoin::Transaction,
     spell_vk: &str,
-) -> anyhow::Result<NormalizedSpell> {
-    let Some((spell_tx_in, tx_ins)) = tx.input.split_last() else {
-        bail!("transaction does not have inputs")
+) -> Result<NormalizedSpell, SpellError> {
+    extract_and_verify_spell_with(tx, &SpellVersions::cached(spell_vk))
+}
+
+/// Extract a [`NormalizedSpell`] from a transaction and verify it, accepting only the spell
+/// versions in `versions`.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn extract_and_verify_spell_with(
+    tx: &bitcoin::Transaction,
+    versions: &SpellVersions,
+) -> Result<NormalizedSpell, SpellError> {
+    let Some(spell_tx_in) = tx.input.last() else {
+        return Err(SpellError::NoInputs);
     };
 
     let (spell, proof) = parse_spell_and_proof(spell_tx_in)?;
 
-    ensure!(
-        &spell.tx.outs.len() <= &tx.output.len(),
-        "spell tx outs mismatch"
-    );
-    ensure!(
-        &spell.tx.ins.is_none(),
-        "spell must inherit inputs from the enchanted tx"
-    );
+    verify_spell_proof(tx, spell, &proof, tx.input.len() - 1, versions)
+}
+
+/// Extract and verify spells from a batch of transactions (e.g. a block's worth).
+/// Returns the result of [`extract_and_verify_spell`] for each transaction, in order.
+/// With the `rayon` feature, transactions are processed in parallel.
+pub fn verify_spells(
+    txs: &[bitcoin::Transaction],
+    spell_vk: &str,
+) -> Vec<Result<NormalizedSpell, SpellError>> {
+    verify_spells_with(txs, &SpellVersions::cached(spell_vk))
+}
+
+/// Extract and verify spells from a batch of transactions, accepting only the spell versions in
+/// `versions`.
+#[tracing::instrument(level = "debug", skip_all, fields(num_txs = txs.len()))]
+pub fn verify_spells_with(
+    txs: &[bitcoin::Transaction],
+    versions: &SpellVersions,
+) -> Vec<Result<NormalizedSpell, SpellError>> {
+    #[cfg(feature = "rayon")]
+    use rayon::prelude::*;
+
+    #[cfg(feature = "rayon")]
+    let txs = txs.par_iter();
+    #[cfg(not(feature = "rayon"))]
+    let txs = txs.iter();
+
+    txs.map(|tx| extract_and_verify_spell_with(tx, versions))
+        .collect()
+}
+
+/// Find a spell envelope in the witness of any of the transaction's inputs, without verifying
+/// the spell proof.
+/// Returns the spell (as committed in the transaction), its proof and the index of the input
+/// carrying it, or `None` if no input has a spell envelope. Use [`verify_spell_proof`] to verify
+/// it.
+/// Only the last input with a spell envelope is decoded: a malformed envelope is an error.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn find_spell(
+    tx: &bitcoin::Transaction,
+) -> Result<Option<(NormalizedSpell, Proof, usize)>, SpellError> {
+    // the spell commitment input is supposed to be the last one
+    let Some(i) = tx
+        .input
+        .iter()
+        .rposition(|tx_in| spell_envelope(tx_in).is_some())
+    else {
+        return Ok(None);
+    };
+    let (spell, proof) = parse_spell_and_proof(&tx.input[i])?;
+    Ok(Some((spell, proof, i)))
+}
+
+/// Verify a spell found in a transaction (e.g. with [`find_spell`]), accepting only the spell
+/// versions in `versions`.
+/// Returns the spell with the inputs it inherits from the hosting transaction.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn verify_spell_proof(
+    tx: &bitcoin::Transaction,
+    spell: NormalizedSpell,
+    proof: &[u8],
+    input_index: usize,
+    versions: &SpellVersions,
+) -> Result<NormalizedSpell, SpellError> {
+    let Some((_, tx_ins)) = tx.input.split_last() else {
+        return Err(SpellError::NoInputs);
+    };
+    if input_index != tx_ins.len() {
+        return Err(SpellError::NotInLastInput(input_index));
+    }
+
+    if spell.tx.outs.len() > tx.output.len() {
+        return Err(SpellError::TooManyOutputs {
+            spell_outs: spell.tx.outs.len(),
+            tx_outs: tx.output.len(),
+        });
+    }
+    if spell.tx.ins.is_some() {
+        return Err(SpellError::NotInherited("inputs"));
+    }
 
     let spell = spell_with_ins(spell, tx_ins);
 
-    let (spell_vk, groth16_vk) = vks(spell.version, spell_vk)?;
+    let version = versions.get(spell.version)?;
+    let spell_vk = version.spell_vk.as_str();
 
     Groth16Verifier::verify(
-        &proof,
-        to_sp1_pv(spell.version, &(spell_vk, &spell)).as_slice(),
+        proof,
+        version
+            .pv_encoding
+            .to_sp1_pv(&(spell_vk, &spell))
+            .as_slice(),
         spell_vk,
-        groth16_vk,
+        &version.groth16_vk,
     )
-    .map_err(|e| anyhow!("could not verify spell proof: {}", e))?;
+    .map_err(|e| SpellError::ProofInvalid(e.to_string()))?;
 
     Ok(spell)
 }
@@ -63,38 +153,201 @@ fn spell_with_ins(spell: NormalizedSpell, spell_tx_ins: &[TxIn]) -> NormalizedSp
     spell
 }
 
-#[tracing::instrument(level = "debug", skip_all)]
-pub fn parse_spell_and_proof(spell_tx_in: &TxIn) -> anyhow::Result<(NormalizedSpell, Proof)> {
-    ensure!(
-        spell_tx_in
-            .witness
-            .taproot_control_block()
-            .ok_or(anyhow!("no control block"))?
-            .len()
-            == 33,
-        "the Taproot tree contains more than one leaf: only a single script is supported"
-    );
-
-    let script = spell_tx_in
-        .witness
-        .tapscript()
-        .ok_or(anyhow!("no spell data in the last input's witness"))?;
-
-    let mut instructions = script.instructions();
+/// Strip a previous transaction of the witness data the spell checker doesn't need.
+/// Only the spell commitment input's witness is kept, and only if the transaction has a spell
+/// envelope.
+/// The transaction ID does not depend on witness data, so it stays the same.
+pub fn strip_prev_tx(tx: &bitcoin::Transaction) -> bitcoin::Transaction {
+    let keep_spell_witness = tx
+        .input
+        .last()
+        .is_some_and(|spell_tx_in| spell_envelope(spell_tx_in).is_some());
 
-    ensure!(instructions.next() == Some(Ok(Instruction::PushBytes(PushBytes::empty()))));
-    ensure!(instructions.next() == Some(Ok(Instruction::Op(OP_IF))));
-    let Some(Ok(Instruction::PushBytes(push_bytes))) = instructions.next() else {
-        bail!("no spell data")
+    let mut tx = tx.clone();
+    let num_stripped = match keep_spell_witness {
+        true => tx.input.len() - 1,
+        false => tx.input.len(),
     };
-    if push_bytes.as_bytes() != b"spell" {
-        bail!("no spell marker")
+    for tx_in in tx.input.iter_mut().take(num_stripped) {
+        tx_in.witness.clear();
+    }
+    tx
+}
+
+/// Compact form of a previous transaction, as passed to the spell checker (for spell versions
+/// with [`SpellFeatures`]`.compact_prev_txs`): the spell checker computes the transaction ID by
+/// hashing `tx` directly, without re-serializing the transaction.
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompactPrevTx {
+    /// Serialization of the transaction without witness data.
+    #[serde_as(as = "Bytes")]
+    pub tx: Vec<u8>,
+    /// Witness of the spell commitment input, if the transaction has a spell envelope.
+    pub spell_witness: Option<Witness>,
+}
+
+impl CompactPrevTx {
+    /// Compact form of a previous transaction: like [`strip_prev_tx`], only the spell commitment
+    /// input's witness is kept, and only if the transaction has a spell envelope.
+    pub fn new(tx: &bitcoin::Transaction) -> Self {
+        let spell_witness = tx
+            .input
+            .last()
+            .filter(|spell_tx_in| spell_envelope(spell_tx_in).is_some())
+            .map(|spell_tx_in| spell_tx_in.witness.clone());
+
+        let mut tx = tx.clone();
+        for tx_in in tx.input.iter_mut() {
+            tx_in.witness.clear();
+        }
+        Self {
+            tx: bitcoin::consensus::serialize(&tx),
+            spell_witness,
+        }
+    }
+
+    /// Return the transaction ID and the transaction (with the spell commitment input's witness).
+    pub fn decode(&self) -> Result<(TxId, bitcoin::Transaction), SpellError> {
+        let tx_id = TxId(sha256d::Hash::hash(&self.tx).to_byte_array());
+        let mut tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&self.tx)
+            .map_err(|e| SpellError::MalformedPrevTx(e.to_string()))?;
+        // with witness data, the hash would not be the transaction ID
+        if tx.input.iter().any(|tx_in| !tx_in.witness.is_empty()) {
+            return Err(SpellError::MalformedPrevTx(
+                "compact prev tx must not have witness data".to_string(),
+            ));
+        }
+        if let Some(spell_witness) = &self.spell_witness {
+            let Some(spell_tx_in) = tx.input.last_mut() else {
+                return Err(SpellError::MalformedPrevTx(
+                    "spell witness for a tx without inputs".to_string(),
+                ));
+            };
+            spell_tx_in.witness = spell_witness.clone();
+        }
+        Ok((tx_id, tx))
+    }
+}
+
+/// Marker (pushed right after `b"spell"` in the envelope) of DEFLATE-compressed spell data.
+const DEFLATE_MARKER: u8 = 1;
+
+/// Maximum size of decompressed spell data: a spell can't be larger than a block.
+const MAX_SPELL_DATA_SIZE: usize = 4_000_000;
+
+/// Encoding of the spell data (CBOR-serialized `(NormalizedSpell, Proof)`) in the spell envelope.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SpellEncoding {
+    /// Plain CBOR: the data follows `b"spell"` right away.
+    Cbor,
+    /// DEFLATE-compressed CBOR (version 3 and later): a format marker follows `b"spell"`.
+    Deflate,
+}
+
+impl SpellEncoding {
+    /// Format marker, pushed right after `b"spell"` in the envelope (`None` for plain CBOR).
+    pub fn marker(self) -> Option<u8> {
+        match self {
+            Self::Cbor => None,
+            Self::Deflate => Some(DEFLATE_MARKER),
+        }
+    }
+
+    fn from_marker(marker: u8) -> Result<Self, SpellError> {
+        match marker {
+            DEFLATE_MARKER => Ok(Self::Deflate),
+            _ => Err(SpellError::MalformedEnvelope(format!(
+                "unknown spell data format: {}",
+                marker
+            ))),
+        }
+    }
+
+    /// Encode CBOR spell data.
+    pub fn encode(self, cbor: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Cbor => cbor.to_vec(),
+            Self::Deflate => miniz_oxide::deflate::compress_to_vec(cbor, 10),
+        }
+    }
+
+    fn decode(self, data: Vec<u8>) -> Result<Vec<u8>, SpellError> {
+        match self {
+            Self::Cbor => Ok(data),
+            Self::Deflate => {
+                miniz_oxide::inflate::decompress_to_vec_with_limit(&data, MAX_SPELL_DATA_SIZE)
+                    .map_err(|e| SpellError::MalformedSpell(e.to_string()))
+            }
+        }
     }
+}
+
+/// Serialize a spell and its proof for the spell envelope, in the smaller of the encodings
+/// supported by the spell's version.
+/// Returns the encoding and the encoded data.
+pub fn encode_spell_data(spell: &NormalizedSpell, proof: &[u8]) -> (SpellEncoding, Vec<u8>) {
+    let cbor = util::write(&(spell, proof)).unwrap();
+    if !SpellFeatures::of(spell.version).compression {
+        return (SpellEncoding::Cbor, cbor);
+    }
+    let compressed = SpellEncoding::Deflate.encode(&cbor);
+    // the format marker takes 2 bytes in the script
+    match compressed.len() + 2 < cbor.len() {
+        true => (SpellEncoding::Deflate, compressed),
+        false => (SpellEncoding::Cbor, cbor),
+    }
+}
 
+/// Instructions of the spell envelope (`OP_FALSE OP_IF "spell" ...`) in the input's Tapscript,
+/// following the `"spell"` push. `None` if the input has no spell envelope.
+fn spell_envelope(tx_in: &TxIn) -> Option<Instructions<'_>> {
+    let mut instructions = tx_in.witness.tapscript()?.instructions();
+    if instructions.next() != Some(Ok(Instruction::PushBytes(PushBytes::empty())))
+        || instructions.next() != Some(Ok(Instruction::Op(OP_IF)))
+    {
+        return None;
+    }
+    match instructions.next() {
+        Some(Ok(Instruction::PushBytes(push_bytes))) if push_bytes.as_bytes() == b"spell" => {
+            Some(instructions)
+        }
+        _ => None,
+    }
+}
+
+/// Parse the spell and its proof from the spell commitment input's witness.
+/// Returns [`SpellError::NoSpell`] if the input does not have a spell envelope.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn parse_spell_and_proof(spell_tx_in: &TxIn) -> Result<(NormalizedSpell, Proof), SpellError> {
+    let Some(mut instructions) = spell_envelope(spell_tx_in) else {
+        return Err(SpellError::NoSpell);
+    };
+
+    // the spell script may be a leaf of a larger Taproot tree, e.g. alongside a refund leaf
+    let Some(control_block) = spell_tx_in
+        .witness
+        .taproot_control_block()
+        .and_then(|control_block| ControlBlock::decode(control_block).ok())
+    else {
+        return Err(SpellError::MalformedEnvelope(
+            "invalid Taproot control block".to_string(),
+        ));
+    };
+
+    let mut encoding = SpellEncoding::Cbor;
     let mut spell_data = vec![];
 
     loop {
         match instructions.next() {
+            // a single-byte push can't be (the start of) CBOR spell data: it's a format marker
+            Some(Ok(Instruction::PushBytes(push_bytes)))
+                if spell_data.is_empty()
+                    && encoding == SpellEncoding::Cbor
+                    && push_bytes.len() == 1 =>
+            {
+                encoding = SpellEncoding::from_marker(push_bytes.as_bytes()[0])?;
+            }
             Some(Ok(Instruction::PushBytes(push_bytes))) => {
                 spell_data.extend(push_bytes.as_bytes());
             }
@@ -102,44 +355,31 @@ pub fn parse_spell_and_proof(spell_tx_in: &TxIn) -> anyhow::Result<(NormalizedSp
                 break;
             }
             _ => {
-                bail!("unexpected opcode")
+                return Err(SpellError::MalformedEnvelope(
+                    "unexpected opcode".to_string(),
+                ));
             }
         }
     }
 
-    let (spell, proof): (NormalizedSpell, Proof) = util::read(spell_data.as_slice())
-        .map_err(|e| anyhow!("could not parse spell and proof: {}", e))?;
-    Ok((spell, proof))
-}
+    let spell_data = encoding.decode(spell_data)?;
+    let (spell, proof): (NormalizedSpell, Proof) =
+        util::read(spell_data.as_slice()).map_err(|e| SpellError::MalformedSpell(e.to_string()))?;
 
-fn vks(spell_version: u32, spell_vk: &str) -> anyhow::Result<(&str, &[u8])> {
-    match spell_version {
-        CURRENT_VERSION => Ok((spell_vk, *sp1_verifier::GROTH16_VK_BYTES)),
-        V1 => Ok((V1_SPELL_VK, *sp1_verifier::GROTH16_VK_BYTES)),
-        V0 => Ok((V0_SPELL_VK, V0_GROTH16_VK_BYTES)),
-        _ => bail!("unsupported spell version: {}", spell_version),
+    let features = SpellFeatures::of(spell.version);
+    if !features.compression && encoding != SpellEncoding::Cbor {
+        return Err(SpellError::MalformedEnvelope(format!(
+            "{:?} spell data is not supported by spell version {}",
+            encoding, spell.version
+        )));
     }
-}
-
-const V0_GROTH16_VK_BYTES: &'static [u8] = include_bytes!("../vk/v0/groth16_vk.bin");
 
-fn to_sp1_pv<T: Serialize>(spell_version: u32, t: &T) -> SP1PublicValues {
-    let mut pv = SP1PublicValues::new();
-    match spell_version {
-        CURRENT_VERSION => {
-            // we commit to CBOR-encoded tuple `(spell_vk, n_spell)`
-            pv.write_slice(util::write(t).unwrap().as_slice());
-        }
-        V1 => {
-            // we commit to CBOR-encoded tuple `(spell_vk, n_spell)`
-            pv.write_slice(util::write(t).unwrap().as_slice());
-        }
-        V0 => {
-            // we used to commit to the tuple `(spell_vk, n_spell)`, which was serialized internally
-            // by SP1
-            pv.write(t);
-        }
-        _ => unreachable!(),
+    if !features.taproot_tree && !control_block.merkle_branch.is_empty() {
+        return Err(SpellError::MalformedEnvelope(
+            "the Taproot tree contains more than one leaf: only a single script is supported"
+                .to_string(),
+        ));
     }
-    pv
+
+    Ok((spell, proof))
 }
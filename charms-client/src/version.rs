@@ -0,0 +1,212 @@
+use crate::{SpellError, CURRENT_VERSION, V0, V0_SPELL_VK, V1, V1_SPELL_VK, V2, V2_SPELL_VK};
+use bitcoin::hashes::serde::Serialize;
+use charms_data::util;
+use sp1_primitives::io::SP1PublicValues;
+use std::{borrow::Cow, collections::BTreeMap, sync::OnceLock};
+
+const V0_GROTH16_VK_BYTES: &[u8] = include_bytes!("../vk/v0/groth16_vk.bin");
+
+/// How a spell version commits to the tuple `(spell_vk, n_spell)` in the proof's public values.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PublicValuesEncoding {
+    /// CBOR-encoded tuple (versions `1` and later).
+    Cbor,
+    /// Tuple serialized internally by SP1 (version `0`).
+    Sp1,
+}
+
+impl PublicValuesEncoding {
+    /// Encode `t` (the tuple `(spell_vk, n_spell)`) as SP1 public values.
+    pub fn to_sp1_pv<T: Serialize>(self, t: &T) -> SP1PublicValues {
+        let mut pv = SP1PublicValues::new();
+        match self {
+            Self::Cbor => pv.write_slice(util::write(t).unwrap().as_slice()),
+            Self::Sp1 => pv.write(t),
+        }
+        pv
+    }
+}
+
+/// What spells of a protocol version may contain and commit to.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpellFeatures {
+    /// The spell script may be a leaf of a multi-leaf Taproot tree.
+    pub taproot_tree: bool,
+    /// The spell data may be compressed.
+    pub compression: bool,
+    /// The spell checker takes previous transactions in compact form (see
+    /// [`CompactPrevTx`](crate::tx::CompactPrevTx)).
+    pub compact_prev_txs: bool,
+}
+
+impl SpellFeatures {
+    /// Features of spells of protocol version `version`.
+    pub const fn of(version: u32) -> Self {
+        match version {
+            V0 | V1 | V2 => Self {
+                taproot_tree: false,
+                compression: false,
+                compact_prev_txs: false,
+            },
+            // V3 and later
+            _ => Self {
+                taproot_tree: true,
+                compression: true,
+                compact_prev_txs: true,
+            },
+        }
+    }
+}
+
+/// What it takes to verify spell proofs of a protocol version.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpellVersion {
+    /// Verification key of the `charms-spell-checker` binary for this version.
+    pub spell_vk: String,
+    /// Groth16 verification key (bytes) the proofs are verified with.
+    pub groth16_vk: Vec<u8>,
+    /// Encoding of the public values committed to by the proofs.
+    pub pv_encoding: PublicValuesEncoding,
+}
+
+impl SpellVersion {
+    fn new(spell_vk: &str, groth16_vk: &[u8], pv_encoding: PublicValuesEncoding) -> Self {
+        Self {
+            spell_vk: spell_vk.to_string(),
+            groth16_vk: groth16_vk.to_vec(),
+            pv_encoding,
+        }
+    }
+}
+
+/// Registry of the spell versions a verifier accepts, keyed by protocol version.
+///
+/// [`SpellVersions::new`] has all versions known to this crate. Downstream verifiers can add
+/// versions with [`SpellVersions::insert`] or pin a subset with [`SpellVersions::only`].
+/// What spells of each version may contain is described by [`SpellFeatures::of`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpellVersions(BTreeMap<u32, SpellVersion>);
+
+static KNOWN_VERSIONS: OnceLock<SpellVersions> = OnceLock::new();
+
+impl SpellVersions {
+    /// All versions known to this crate. `spell_vk` is the verification key of the current
+    /// version of the `charms-spell-checker` binary.
+    pub fn new(spell_vk: &str) -> Self {
+        let mut versions = Self::known().clone();
+        versions.insert(
+            CURRENT_VERSION,
+            SpellVersion::new(
+                spell_vk,
+                *sp1_verifier::GROTH16_VK_BYTES,
+                PublicValuesEncoding::Cbor,
+            ),
+        );
+        versions
+    }
+
+    /// Same as [`SpellVersions::new`], but built once: borrowed from a static registry unless
+    /// `spell_vk` differs from the pinned verification key of the current version.
+    pub fn cached(spell_vk: &str) -> Cow<'static, Self> {
+        let known = Self::known();
+        match known.get(CURRENT_VERSION) {
+            Ok(current) if current.spell_vk == spell_vk => Cow::Borrowed(known),
+            _ => Cow::Owned(Self::new(spell_vk)),
+        }
+    }
+
+    /// Versions with pinned verification keys.
+    fn known() -> &'static Self {
+        KNOWN_VERSIONS.get_or_init(Self::pinned)
+    }
+
+    fn pinned() -> Self {
+        let groth16_vk = *sp1_verifier::GROTH16_VK_BYTES;
+        let mut versions = Self::default();
+        versions.insert(
+            V0,
+            SpellVersion::new(V0_SPELL_VK, V0_GROTH16_VK_BYTES, PublicValuesEncoding::Sp1),
+        );
+        versions.insert(
+            V1,
+            SpellVersion::new(V1_SPELL_VK, groth16_vk, PublicValuesEncoding::Cbor),
+        );
+        versions.insert(
+            V2,
+            SpellVersion::new(V2_SPELL_VK, groth16_vk, PublicValuesEncoding::Cbor),
+        );
+        versions
+    }
+
+    /// Keep only the listed versions.
+    pub fn only(mut self, versions: &[u32]) -> Self {
+        self.0.retain(|version, _| versions.contains(version));
+        self
+    }
+
+    /// Add (or replace) a version. Returns the version it replaced, if any.
+    pub fn insert(&mut self, version: u32, spell_version: SpellVersion) -> Option<SpellVersion> {
+        self.0.insert(version, spell_version)
+    }
+
+    /// Remove a version. Returns it, if it was registered.
+    pub fn remove(&mut self, version: u32) -> Option<SpellVersion> {
+        self.0.remove(&version)
+    }
+
+    /// Get a version, or [`SpellError::UnsupportedVersion`] if it is not registered.
+    pub fn get(&self, version: u32) -> Result<&SpellVersion, SpellError> {
+        self.0
+            .get(&version)
+            .ok_or(SpellError::UnsupportedVersion(version))
+    }
+
+    /// Registered protocol versions, in ascending order.
+    pub fn versions(&self) -> impl Iterator<Item = u32> + '_ {
+        self.0.keys().copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::V3;
+
+    #[test]
+    fn pin_versions() {
+        let versions = SpellVersions::new("0xcafe").only(&[V1]);
+        assert_eq!(versions.versions().collect::<Vec<_>>(), vec![V1]);
+        assert_eq!(versions.get(V1).unwrap().spell_vk, V1_SPELL_VK);
+        assert_eq!(
+            versions.get(CURRENT_VERSION),
+            Err(SpellError::UnsupportedVersion(CURRENT_VERSION))
+        );
+
+        let versions = SpellVersions::new("0xcafe");
+        assert_eq!(versions.get(CURRENT_VERSION).unwrap().spell_vk, "0xcafe");
+        assert_eq!(
+            versions.get(V0).unwrap().pv_encoding,
+            PublicValuesEncoding::Sp1
+        );
+
+        let current_vk = &SpellVersions::known()
+            .get(CURRENT_VERSION)
+            .unwrap()
+            .spell_vk;
+        assert!(matches!(
+            SpellVersions::cached(current_vk),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(
+            SpellVersions::cached("0xcafe").into_owned(),
+            SpellVersions::new("0xcafe")
+        );
+    }
+
+    #[test]
+    fn spell_features() {
+        assert_eq!(SpellFeatures::of(V2), SpellFeatures::default());
+        assert!(SpellFeatures::of(V3).compression);
+        assert!(SpellFeatures::of(V3).compact_prev_txs);
+    }
+}
@@ -0,0 +1,102 @@
+//! JavaScript bindings (`wasm` feature), so that browsers can verify and display spells from
+//! raw transactions without calling a server.
+//!
+//! Build with `cargo build -p charms-client --target wasm32-unknown-unknown --features wasm` and
+//! generate the JavaScript glue with `wasm-bindgen`.
+
+use crate::{checked_to_tx, denormalized, tx, NormalizedSpell, SpellVersions};
+use bitcoin::consensus::encode::deserialize_hex;
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::prelude::*;
+
+fn to_js<T: Serialize>(value: &T) -> Result<JsValue, JsError> {
+    Ok(value.serialize(&serde_wasm_bindgen::Serializer::json_compatible())?)
+}
+
+fn from_js<T: DeserializeOwned>(value: JsValue) -> Result<T, JsError> {
+    Ok(serde_wasm_bindgen::from_value(value)?)
+}
+
+fn parse_tx(tx_hex: &str) -> Result<bitcoin::Transaction, JsError> {
+    deserialize_hex(tx_hex).map_err(|e| JsError::new(&format!("invalid transaction: {}", e)))
+}
+
+/// Extract the spell from a (hex-encoded) transaction and verify it.
+/// Returns the normalized spell, or `undefined` if the transaction does not have a spell.
+/// Throws if the spell is incorrect.
+#[wasm_bindgen(js_name = extractAndVerifySpell)]
+pub fn extract_and_verify_spell_js(tx_hex: &str, spell_vk: &str) -> Result<JsValue, JsError> {
+    match tx::extract_and_verify_spell(&parse_tx(tx_hex)?, spell_vk) {
+        Ok(spell) => to_js(&spell),
+        Err(crate::SpellError::NoSpell) => Ok(JsValue::UNDEFINED),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// De-normalize a normalized spell (as returned by `extractAndVerifySpell`) for display: charms
+/// are keyed by app keys (`$0000`, `$0001`, ...).
+#[wasm_bindgen(js_name = denormalizeSpell)]
+pub fn denormalize_spell_js(spell: JsValue) -> Result<JsValue, JsError> {
+    to_js(&denormalized(&from_js(spell)?))
+}
+
+/// Charms transaction (inputs and outputs with their charms) of a normalized spell (as returned
+/// by `extractAndVerifySpell`). `prev_txs` are the hex-encoded transactions creating the spell's
+/// inputs and references. Throws if the spell version is not supported or the spell is not
+/// well-formed.
+#[wasm_bindgen(js_name = toTx)]
+pub fn to_tx_js(spell: JsValue, prev_txs: Vec<String>, spell_vk: &str) -> Result<JsValue, JsError> {
+    let spell: NormalizedSpell = from_js(spell)?;
+    let prev_txs = prev_txs
+        .iter()
+        .map(|tx_hex| parse_tx(tx_hex))
+        .collect::<Result<Vec<_>, _>>()?;
+    to_js(&checked_to_tx(
+        &spell,
+        &prev_txs,
+        &SpellVersions::cached(spell_vk),
+    )?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{NormalizedCharms, NormalizedTransaction, SpellError, V2, V3};
+    use charms_data::{Data, TxId, UtxoId};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn to_tx_of_earlier_versions() {
+        let mut spell = NormalizedSpell {
+            version: V2,
+            tx: NormalizedTransaction {
+                ins: Some(vec![]),
+                refs: BTreeSet::new(),
+                outs: vec![NormalizedCharms::new()],
+            },
+            app_public_inputs: BTreeMap::new(),
+        };
+        let versions = SpellVersions::cached("");
+
+        let tx = checked_to_tx(&spell, &[], &versions).unwrap();
+        assert_eq!(tx.outs.len(), 1);
+
+        // not released yet
+        spell.version = V3;
+        assert_eq!(
+            checked_to_tx(&spell, &[], &versions),
+            Err(SpellError::UnsupportedVersion(V3))
+        );
+
+        spell.version = 99;
+        assert_eq!(
+            checked_to_tx(&spell, &[], &versions),
+            Err(SpellError::UnsupportedVersion(99))
+        );
+
+        spell.version = V2;
+        spell.tx.ins = Some(vec![UtxoId(TxId([1; 32]), 0)]);
+        spell.tx.outs[0] = NormalizedCharms::from([(0, Data::from(&1u64))]);
+        assert!(checked_to_tx(&spell, &[], &versions).is_err());
+    }
+}
@@ -14,6 +14,8 @@ use serde::{
     ser::SerializeTuple,
     Deserialize, Deserializer, Serialize, Serializer,
 };
+#[cfg(feature = "schemars")]
+mod schema;
 pub mod util;
 
 /// Macro to check a condition and return false (early) if it does not hold.
@@ -0,0 +1,100 @@
+//! [`JsonSchema`] implementations: schemas of the human-readable (e.g. JSON) forms of the types.
+
+use crate::{App, Data, UtxoId, B32};
+use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+use std::borrow::Cow;
+
+impl JsonSchema for B32 {
+    fn schema_name() -> Cow<'static, str> {
+        "B32".into()
+    }
+
+    fn schema_id() -> Cow<'static, str> {
+        "charms_data::B32".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "32-byte byte string (e.g. a hash), hex-encoded.",
+            "type": "string",
+            "pattern": "^[0-9a-fA-F]{64}$",
+        })
+    }
+}
+
+impl JsonSchema for UtxoId {
+    fn schema_name() -> Cow<'static, str> {
+        "UtxoId".into()
+    }
+
+    fn schema_id() -> Cow<'static, str> {
+        "charms_data::UtxoId".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "UTXO ID as `txid:vout`.",
+            "type": "string",
+            "pattern": "^[0-9a-fA-F]{64}:[0-9]+$",
+        })
+    }
+}
+
+impl JsonSchema for App {
+    fn schema_name() -> Cow<'static, str> {
+        "App".into()
+    }
+
+    fn schema_id() -> Cow<'static, str> {
+        "charms_data::App".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "App as `tag/identity/vk`: a single character tag (`t` for tokens, `n` for NFTs), hex-encoded 32-byte identity and verification key.",
+            "type": "string",
+            "pattern": "^./[0-9a-fA-F]{64}/[0-9a-fA-F]{64}$",
+        })
+    }
+}
+
+impl JsonSchema for Data {
+    fn schema_name() -> Cow<'static, str> {
+        "Data".into()
+    }
+
+    fn schema_id() -> Cow<'static, str> {
+        "charms_data::Data".into()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "description": "App data: any value, e.g. token amount (integer) or NFT state (object).",
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::TxId;
+    use regex::Regex;
+
+    fn pattern<T: JsonSchema>() -> Regex {
+        let schema = schemars::schema_for!(T);
+        Regex::new(schema.get("pattern").unwrap().as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn patterns_match_display() {
+        let app = App {
+            tag: 't',
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        assert!(pattern::<App>().is_match(&app.to_string()));
+        assert!(pattern::<B32>().is_match(&app.vk.to_string()));
+        assert!(pattern::<UtxoId>().is_match(&UtxoId(TxId([3; 32]), 1).to_string()));
+        assert!(!pattern::<B32>().is_match(&UtxoId(TxId([3; 32]), 1).to_string()));
+    }
+}
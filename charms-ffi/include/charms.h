@@ -0,0 +1,63 @@
+#ifndef CHARMS_H
+#define CHARMS_H
+
+/* Generated by cbindgen from charms-ffi: do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Extract the spell from a (consensus-encoded) transaction and verify it.
+//
+// `tx_bytes` points to `tx_len` bytes of the transaction. `spell_vk` is the verification key of
+// the current version of the spell checker.
+//
+// Returns `{"ok": <normalized spell>}`, `{"ok": null}` if the transaction does not have a spell,
+// or `{"error": "<reason>"}` if the spell is incorrect.
+//
+// # Safety
+//
+// `tx_bytes` must point to `tx_len` readable bytes, and `spell_vk` to a NUL-terminated string.
+char *charms_extract_and_verify_spell(const uint8_t *tx_bytes, size_t tx_len, const char *spell_vk);
+
+// Compute the charms transaction (inputs and outputs with their charms) of a spell.
+//
+// `spell_json` is a normalized spell (as returned by [`charms_extract_and_verify_spell`]).
+// `prev_txs` and `prev_tx_lens` are arrays of `num_prev_txs` (consensus-encoded) transactions
+// creating the spell's inputs and references, and their lengths.
+//
+// Returns `{"ok": <transaction>}`, or `{"error": "<reason>"}` if the spell version is not
+// supported or the spell is not well-formed.
+//
+// # Safety
+//
+// `spell_json` and `spell_vk` must point to NUL-terminated strings. `prev_txs` and
+// `prev_tx_lens` must point to `num_prev_txs` elements each, and each `prev_txs[i]` to
+// `prev_tx_lens[i]` readable bytes.
+char *charms_to_tx(const char *spell_json,
+                   const uint8_t *const *prev_txs,
+                   const size_t *prev_tx_lens,
+                   size_t num_prev_txs,
+                   const char *spell_vk);
+
+// Release a string returned by a `charms_*` function. Does nothing if `s` is NULL.
+//
+// # Safety
+//
+// `s` must be NULL or a string returned by a `charms_*` function, not yet released.
+void charms_string_free(char *s);
+
+// Version of the Charms protocol spells are created with.
+uint32_t charms_protocol_version(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CHARMS_H */
@@ -0,0 +1,292 @@
+//! C bindings for verifying spells, so that services written in other languages don't have to
+//! reimplement the spell envelope parsing and proof verification.
+//!
+//! Functions returning `char *` return NUL-terminated JSON: `{"ok": <result>}` on success or
+//! `{"error": "<message>"}` on failure. Returned strings are owned by the caller and **must** be
+//! released with [`charms_string_free`]. Panics are caught and returned as errors.
+//! The C header is checked in at `include/charms.h`: the tests check it is up to date with the
+//! header generated when the crate is built.
+
+use bitcoin::consensus::encode::deserialize;
+use charms_client::{checked_to_tx, tx, NormalizedSpell, SpellError, SpellVersions};
+use serde::Serialize;
+use std::{
+    ffi::{c_char, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    slice,
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum FfiResult<T> {
+    Ok(T),
+    Error(String),
+}
+
+/// Run `f`, returning its result as a JSON string. A panic is returned as an error: it must not
+/// unwind into the caller.
+fn ffi_call<T: Serialize>(f: impl FnOnce() -> Result<T, String>) -> *mut c_char {
+    let result = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+            (Some(message), _) => message,
+            (_, Some(message)) => message.as_str(),
+            _ => "unknown error",
+        };
+        Err(format!("panic: {}", message))
+    });
+    to_c_string(result)
+}
+
+fn to_c_string<T: Serialize>(result: Result<T, String>) -> *mut c_char {
+    let result = match result {
+        Ok(value) => FfiResult::Ok(value),
+        Err(e) => FfiResult::Error(e),
+    };
+    let json = serde_json::to_string(&result)
+        .unwrap_or_else(|e| format!(r#"{{"error":"could not serialize result: {}"}}"#, e));
+    CString::new(json)
+        .expect("JSON should not contain NUL bytes")
+        .into_raw()
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{} is NULL", name));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| format!("{} is not valid UTF-8: {}", name, e))
+}
+
+unsafe fn bytes_arg<'a>(bytes: *const u8, len: usize, name: &str) -> Result<&'a [u8], String> {
+    match bytes.is_null() {
+        true if len == 0 => Ok(&[]),
+        true => Err(format!("{} is NULL", name)),
+        false => Ok(slice::from_raw_parts(bytes, len)),
+    }
+}
+
+unsafe fn tx_arg(bytes: *const u8, len: usize, name: &str) -> Result<bitcoin::Transaction, String> {
+    deserialize(bytes_arg(bytes, len, name)?).map_err(|e| format!("invalid {}: {}", name, e))
+}
+
+/// Extract the spell from a (consensus-encoded) transaction and verify it.
+///
+/// `tx_bytes` points to `tx_len` bytes of the transaction. `spell_vk` is the verification key of
+/// the current version of the spell checker.
+///
+/// Returns `{"ok": <normalized spell>}`, `{"ok": null}` if the transaction does not have a spell,
+/// or `{"error": "<reason>"}` if the spell is incorrect.
+///
+/// # Safety
+///
+/// `tx_bytes` must point to `tx_len` readable bytes, and `spell_vk` to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn charms_extract_and_verify_spell(
+    tx_bytes: *const u8,
+    tx_len: usize,
+    spell_vk: *const c_char,
+) -> *mut c_char {
+    ffi_call(|| {
+        let tx = tx_arg(tx_bytes, tx_len, "tx")?;
+        let spell_vk = str_arg(spell_vk, "spell_vk")?;
+        match tx::extract_and_verify_spell(&tx, spell_vk) {
+            Ok(spell) => Ok(Some(spell)),
+            Err(SpellError::NoSpell) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    })
+}
+
+/// Compute the charms transaction (inputs and outputs with their charms) of a spell.
+///
+/// `spell_json` is a normalized spell (as returned by [`charms_extract_and_verify_spell`]).
+/// `prev_txs` and `prev_tx_lens` are arrays of `num_prev_txs` (consensus-encoded) transactions
+/// creating the spell's inputs and references, and their lengths.
+///
+/// Returns `{"ok": <transaction>}`, or `{"error": "<reason>"}` if the spell version is not
+/// supported or the spell is not well-formed.
+///
+/// # Safety
+///
+/// `spell_json` and `spell_vk` must point to NUL-terminated strings. `prev_txs` and
+/// `prev_tx_lens` must point to `num_prev_txs` elements each, and each `prev_txs[i]` to
+/// `prev_tx_lens[i]` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn charms_to_tx(
+    spell_json: *const c_char,
+    prev_txs: *const *const u8,
+    prev_tx_lens: *const usize,
+    num_prev_txs: usize,
+    spell_vk: *const c_char,
+) -> *mut c_char {
+    ffi_call(|| {
+        let spell: NormalizedSpell = serde_json::from_str(str_arg(spell_json, "spell_json")?)
+            .map_err(|e| format!("invalid spell_json: {}", e))?;
+        let prev_tx_ptrs = if num_prev_txs == 0 {
+            &[]
+        } else if prev_txs.is_null() || prev_tx_lens.is_null() {
+            return Err("prev_txs is NULL".to_string());
+        } else {
+            slice::from_raw_parts(prev_txs, num_prev_txs)
+        };
+        let prev_tx_lens = match num_prev_txs {
+            0 => &[],
+            _ => slice::from_raw_parts(prev_tx_lens, num_prev_txs),
+        };
+        let prev_txs = prev_tx_ptrs
+            .iter()
+            .zip(prev_tx_lens)
+            .map(|(&bytes, &len)| tx_arg(bytes, len, "prev_tx"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let spell_vk = str_arg(spell_vk, "spell_vk")?;
+
+        checked_to_tx(&spell, &prev_txs, &SpellVersions::cached(spell_vk))
+            .map_err(|e| e.to_string())
+    })
+}
+
+/// Release a string returned by a `charms_*` function. Does nothing if `s` is NULL.
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by a `charms_*` function, not yet released.
+#[no_mangle]
+pub unsafe extern "C" fn charms_string_free(s: *mut c_char) {
+    if s.is_null() {
+        return;
+    }
+    // a panic must not unwind into the caller
+    let _ = panic::catch_unwind(|| drop(CString::from_raw(s)));
+}
+
+/// Version of the Charms protocol spells are created with.
+#[no_mangle]
+pub extern "C" fn charms_protocol_version() -> u32 {
+    charms_client::CURRENT_VERSION
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{absolute::LockTime, consensus::encode::serialize, transaction::Version};
+    use charms_client::{NormalizedTransaction, CURRENT_VERSION, V2};
+    use std::{collections::BTreeMap, ptr};
+
+    unsafe fn take_string(s: *mut c_char) -> String {
+        let string = CStr::from_ptr(s).to_str().unwrap().to_string();
+        charms_string_free(s);
+        string
+    }
+
+    #[test]
+    fn extract_and_verify_spell() {
+        let tx = bitcoin::Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![Default::default()],
+            output: vec![],
+        };
+        let tx_bytes = serialize(&tx);
+        let vk = CString::new("").unwrap();
+
+        unsafe {
+            let result =
+                charms_extract_and_verify_spell(tx_bytes.as_ptr(), tx_bytes.len(), vk.as_ptr());
+            assert_eq!(take_string(result), r#"{"ok":null}"#);
+
+            let result = charms_extract_and_verify_spell(tx_bytes.as_ptr(), 3, vk.as_ptr());
+            assert!(take_string(result).starts_with(r#"{"error":"invalid tx: "#));
+
+            let result =
+                charms_extract_and_verify_spell(tx_bytes.as_ptr(), tx_bytes.len(), ptr::null());
+            assert_eq!(take_string(result), r#"{"error":"spell_vk is NULL"}"#);
+        }
+    }
+
+    #[test]
+    fn to_tx() {
+        let vk = CString::new("").unwrap();
+        let spell = NormalizedSpell {
+            version: CURRENT_VERSION,
+            tx: NormalizedTransaction {
+                ins: Some(vec![]),
+                refs: Default::default(),
+                outs: vec![],
+            },
+            app_public_inputs: BTreeMap::new(),
+        };
+        let spell_json = CString::new(serde_json::to_string(&spell).unwrap()).unwrap();
+
+        unsafe {
+            let result = charms_to_tx(
+                spell_json.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                vk.as_ptr(),
+            );
+            assert!(take_string(result).starts_with(r#"{"ok":{"ins":{}"#));
+
+            let result = charms_to_tx(
+                spell_json.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                1,
+                vk.as_ptr(),
+            );
+            assert_eq!(take_string(result), r#"{"error":"prev_txs is NULL"}"#);
+
+            let bad_json = CString::new("{}").unwrap();
+            let result = charms_to_tx(bad_json.as_ptr(), ptr::null(), ptr::null(), 0, vk.as_ptr());
+            assert!(take_string(result).starts_with(r#"{"error":"invalid spell_json: "#));
+        }
+
+        let v2_spell = NormalizedSpell {
+            version: V2,
+            ..spell.clone()
+        };
+        let v2_spell_json = CString::new(serde_json::to_string(&v2_spell).unwrap()).unwrap();
+        let unsupported_spell = NormalizedSpell {
+            version: 99,
+            ..spell
+        };
+        let unsupported_spell_json =
+            CString::new(serde_json::to_string(&unsupported_spell).unwrap()).unwrap();
+
+        unsafe {
+            let result = charms_to_tx(
+                v2_spell_json.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                vk.as_ptr(),
+            );
+            assert!(take_string(result).starts_with(r#"{"ok":{"ins":{}"#));
+
+            let result = charms_to_tx(
+                unsupported_spell_json.as_ptr(),
+                ptr::null(),
+                ptr::null(),
+                0,
+                vk.as_ptr(),
+            );
+            assert!(take_string(result).starts_with(r#"{"error":"#));
+        }
+    }
+
+    #[test]
+    fn panics_are_errors() {
+        let result = ffi_call(|| -> Result<(), String> { panic!("boom") });
+        assert_eq!(unsafe { take_string(result) }, r#"{"error":"panic: boom"}"#);
+    }
+
+    #[test]
+    fn header_is_up_to_date() {
+        assert_eq!(
+            include_str!("../include/charms.h"),
+            include_str!(concat!(env!("OUT_DIR"), "/charms.h")),
+            "include/charms.h is out of date: copy it from the build's OUT_DIR"
+        );
+    }
+}
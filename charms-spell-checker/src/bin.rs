@@ -20,6 +20,7 @@ pub(crate) fn run(input: SpellProverInput) -> (String, NormalizedSpell) {
     let SpellProverInput {
         self_spell_vk,
         prev_txs,
+        compact_prev_txs,
         spell,
         app_contract_proofs,
     } = input;
@@ -35,12 +36,15 @@ pub(crate) fn run(input: SpellProverInput) -> (String, NormalizedSpell) {
         .collect();
 
     // Check the spell that we're proving is correct.
-    assert!(is_correct(
+    if let Err(e) = is_correct(
         &spell,
         &prev_txs,
+        &compact_prev_txs,
         &app_contract_proofs,
-        &self_spell_vk
-    ));
+        &self_spell_vk,
+    ) {
+        panic!("spell is not correct: {}", e);
+    }
 
     eprintln!("Spell is correct!");
 
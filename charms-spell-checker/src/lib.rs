@@ -2,51 +2,54 @@ pub mod app;
 pub mod bin;
 
 use crate::app::AppContractVK;
-use charms_client::NormalizedSpell;
+use charms_client::{
+    tx::CompactPrevTx, NormalizedSpell, SpellError, SpellVersions, CURRENT_VERSION,
+};
 use charms_data::App;
 
-/// Check if the spell is correct.
+/// Check if the spell is correct. Returns the reason if it is not.
 pub(crate) fn is_correct(
     spell: &NormalizedSpell,
-    prev_txs: &Vec<bitcoin::Transaction>,
+    prev_txs: &[bitcoin::Transaction],
+    compact_prev_txs: &[CompactPrevTx],
     app_contract_vks: &Vec<(App, AppContractVK)>,
     spell_vk: &String,
-) -> bool {
-    let prev_spells = charms_client::prev_spells(prev_txs, spell_vk);
-    if !charms_client::well_formed(spell, &prev_spells) {
-        eprintln!("not well formed");
-        return false;
+) -> Result<(), SpellError> {
+    if spell.version != CURRENT_VERSION {
+        return Err(SpellError::VersionMismatch {
+            version: spell.version,
+            current: CURRENT_VERSION,
+        });
     }
+    let versions = SpellVersions::cached(spell_vk);
+    let mut prev_spells = charms_client::prev_spells_with(prev_txs, &versions);
+    prev_spells.extend(charms_client::compact_prev_spells(
+        compact_prev_txs,
+        &versions,
+    )?);
+    charms_client::check_well_formed(spell, &prev_spells)?;
     let Some(prev_txids) = spell.tx.prev_txids() else {
         unreachable!("the spell is well formed: tx.ins MUST be Some");
     };
     if prev_txids != prev_spells.keys().collect() {
-        eprintln!("spell.tx.prev_txids() != prev_spells.keys()");
-        return false;
+        return Err(SpellError::PrevTxidsMismatch);
     }
 
     let apps = charms_client::apps(spell);
     if apps.len() != app_contract_vks.len() {
-        eprintln!("apps.len() != app_contract_proofs.len()");
-        return false;
+        return Err(SpellError::AppsMismatch {
+            num_apps: apps.len(),
+            num_vks: app_contract_vks.len(),
+        });
     }
-    if !apps
-        .iter()
-        .zip(app_contract_vks)
-        .all(|(app0, (app, proof))| {
-            app == app0
-                && proof.verify(
-                    app,
-                    &charms_client::to_tx(spell, &prev_spells),
-                    &spell.app_public_inputs[app],
-                )
-        })
-    {
-        eprintln!("app_contract_proofs verification failed");
-        return false;
+    let tx = charms_client::to_tx(spell, &prev_spells);
+    for (app0, (app, proof)) in apps.iter().zip(app_contract_vks) {
+        if app != app0 || !proof.verify(app, &tx, &spell.app_public_inputs[app]) {
+            return Err(SpellError::AppContractFailed(app0.clone()));
+        }
     }
 
-    true
+    Ok(())
 }
 
 #[cfg(test)]
@@ -2,7 +2,8 @@ use crate::utils::{BoxedSP1Prover, Shared};
 use anyhow::ensure;
 use charms_data::{is_simple_transfer, util, App, Data, Transaction, B32};
 use sp1_sdk::{
-    HashableKey, ProverClient, SP1Context, SP1Proof, SP1ProofMode, SP1Stdin, SP1VerifyingKey,
+    HashableKey, ProverClient, SP1Context, SP1Proof, SP1ProofMode, SP1ProvingKey, SP1Stdin,
+    SP1VerifyingKey,
 };
 use std::{collections::BTreeMap, mem, sync::Arc};
 
@@ -33,24 +34,30 @@ impl Prover {
         }
     }
 
-    pub(crate) fn prove(
+    /// Set up the proving and verifying keys of app binaries (keyed by app VK).
+    pub(crate) fn setup(
         &self,
         app_binaries: &BTreeMap<B32, Vec<u8>>,
+    ) -> BTreeMap<B32, Arc<(SP1ProvingKey, SP1VerifyingKey)>> {
+        app_binaries
+            .iter()
+            .map(|(vk_hash, binary)| {
+                let pk_vk = self.sp1_client.get().setup(binary);
+                (vk_hash.clone(), Arc::new(pk_vk))
+            })
+            .collect()
+    }
+
+    pub(crate) fn prove(
+        &self,
+        pk_vks: &BTreeMap<B32, Arc<(SP1ProvingKey, SP1VerifyingKey)>>,
         tx: Transaction,
         app_public_inputs: &BTreeMap<App, Data>,
         app_private_inputs: BTreeMap<App, Data>,
         spell_stdin: &mut SP1Stdin,
     ) -> anyhow::Result<()> {
-        let pk_vks = app_binaries
-            .iter()
-            .map(|(vk_hash, binary)| {
-                let (pk, vk) = self.sp1_client.get().setup(binary);
-                (vk_hash, (pk, vk))
-            })
-            .collect::<BTreeMap<_, _>>();
-
         for (app, x) in app_public_inputs {
-            let Some((pk, vk)) = pk_vks.get(&app.vk) else {
+            let Some((pk, vk)) = pk_vks.get(&app.vk).map(|pk_vk| &**pk_vk) else {
                 tracing::info!("app binary not provided: {}", app);
                 continue;
             };
@@ -0,0 +1,292 @@
+use crate::spell::{CharmsFee, Network};
+use anyhow::{anyhow, ensure, Result};
+use bitcoin::{address::NetworkUnchecked, Address};
+use charms_data::{App, UtxoId};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, env, fs, path::PathBuf, str::FromStr};
+
+/// Default URL of the Charms proving API.
+pub const DEFAULT_PROVE_API_URL: &str = "https://prove.charms.dev/spells/prove";
+/// Default Charms fee rate: sats per million cycles of proving.
+pub const DEFAULT_FEE_RATE: u64 = 1000;
+/// Default Charms fee base: sats charged per spell on top of the fee rate.
+pub const DEFAULT_FEE_BASE: u64 = 1000;
+/// Supported SP1 provers (empty for the default, `cpu`).
+const SP1_PROVERS: [&str; 4] = ["", "cpu", "cuda", "network"];
+/// Default bitcoind RPC URL.
+pub const DEFAULT_RPC_URL: &str = "http://localhost:48332";
+/// Default bitcoind RPC user.
+pub const DEFAULT_RPC_USER: &str = "hello";
+/// Default bitcoind RPC password.
+pub const DEFAULT_RPC_PASSWORD: &str = "world";
+
+/// Charms CLI and server settings.
+///
+/// Loaded once from `charms/charms.toml` in the user config directory (e.g. `~/.config` on Linux).
+/// Environment variables override the file, and command line flags override both.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Bitcoin network. Env var: `CHARMS_NETWORK`.
+    pub network: Option<Network>,
+    /// URL of the Charms proving API. Env var: `CHARMS_PROVE_API_URL`.
+    pub prove_api_url: Option<String>,
+    /// SP1 prover to use: `cpu`, `cuda` or `network`. Env var: `SP1_PROVER`.
+    pub sp1_prover: Option<String>,
+    /// Charms fee settings.
+    pub fee: FeeConfig,
+    /// bitcoind RPC settings (used by the server).
+    pub rpc: RpcConfig,
+    /// Local app alias registry: `[apps.NAME]` makes `$NAME` the key of the app in spells.
+    pub apps: BTreeMap<String, AppAlias>,
+}
+
+/// App registered under an alias.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppAlias {
+    /// The app: `tag/identity/vk`.
+    pub app: App,
+    /// Path to the app's RISC-V binary: used if the spell has the app, unless `--app-bins` has it.
+    pub bin: Option<PathBuf>,
+    /// Token metadata (CHIP-420).
+    pub metadata: Option<AppMetadata>,
+}
+
+/// Token metadata, as defined in CHIP-420. All fields are optional, additional fields are allowed.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AppMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ticker: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decimals: Option<u8>,
+    #[serde(rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub utxo_ref: Option<UtxoId>,
+    #[serde(flatten)]
+    pub other: BTreeMap<String, toml::Value>,
+}
+
+/// Charms fee settings. No fee is charged unless `address` is set.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeConfig {
+    /// Address to pay the Charms fee to. Env var: `CHARMS_FEE_ADDRESS`.
+    pub address: Option<Address<NetworkUnchecked>>,
+    /// Sats per million cycles of proving. Env var: `CHARMS_FEE_RATE`.
+    pub rate: Option<u64>,
+    /// Sats charged per spell on top of the fee rate. Env var: `CHARMS_FEE_BASE`.
+    pub base: Option<u64>,
+}
+
+/// bitcoind RPC settings.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    /// bitcoind RPC URL. Env var: `RPC_URL`.
+    pub url: Option<String>,
+    /// bitcoind RPC user. Env var: `RPC_USER`.
+    pub user: Option<String>,
+    /// bitcoind RPC password. Env var: `RPC_PASSWORD`.
+    pub password: Option<String>,
+}
+
+impl Config {
+    /// Load the config file (if it exists) and apply environment variable overrides.
+    pub fn load() -> Result<Self> {
+        let config = match config_file() {
+            Some(path) if path.exists() => {
+                let s = fs::read_to_string(&path)
+                    .map_err(|e| anyhow!("error reading {:?}: {}", &path, e))?;
+                toml::from_str(&s).map_err(|e| anyhow!("error parsing {:?}: {}", &path, e))?
+            }
+            _ => Self::default(),
+        };
+        let config = config.with_env()?;
+        ensure!(
+            SP1_PROVERS.contains(&config.sp1_prover()),
+            "sp1_prover must be one of: cpu, cuda, network"
+        );
+        Ok(config)
+    }
+
+    fn with_env(self) -> Result<Self> {
+        let mut config = self;
+        override_from_env(&mut config.network, "CHARMS_NETWORK", |s| {
+            <Network as ValueEnum>::from_str(s, true)
+                .map_err(|_| anyhow!("must be one of: mainnet, testnet4, signet, regtest"))
+        })?;
+        override_from_env(&mut config.prove_api_url, "CHARMS_PROVE_API_URL", |s| {
+            Ok(s.to_string())
+        })?;
+        override_from_env(&mut config.sp1_prover, "SP1_PROVER", |s| Ok(s.to_string()))?;
+        override_from_env(&mut config.fee.address, "CHARMS_FEE_ADDRESS", |s| {
+            Address::from_str(s).map_err(|e| anyhow!("must be a valid Bitcoin address: {}", e))
+        })?;
+        override_from_env(&mut config.fee.rate, "CHARMS_FEE_RATE", |s| {
+            s.parse()
+                .map_err(|_| anyhow!("must be an unsigned integer"))
+        })?;
+        override_from_env(&mut config.fee.base, "CHARMS_FEE_BASE", |s| {
+            s.parse()
+                .map_err(|_| anyhow!("must be an unsigned integer"))
+        })?;
+        override_from_env(&mut config.rpc.url, "RPC_URL", |s| Ok(s.to_string()))?;
+        override_from_env(&mut config.rpc.user, "RPC_USER", |s| Ok(s.to_string()))?;
+        override_from_env(&mut config.rpc.password, "RPC_PASSWORD", |s| {
+            Ok(s.to_string())
+        })?;
+        Ok(config)
+    }
+
+    /// SP1 prover to use: `cpu`, `cuda` or `network` (empty for the default, `cpu`).
+    pub fn sp1_prover(&self) -> &str {
+        self.sp1_prover.as_deref().unwrap_or_default()
+    }
+
+    /// URL of the Charms proving API.
+    pub fn prove_api_url(&self) -> String {
+        self.prove_api_url
+            .clone()
+            .unwrap_or(DEFAULT_PROVE_API_URL.to_string())
+    }
+
+    /// Charms fee settings, if a fee address is configured.
+    pub fn charms_fee(&self) -> Option<CharmsFee> {
+        self.fee.address.clone().map(|fee_address| CharmsFee {
+            fee_address,
+            fee_rate: self.fee.rate.unwrap_or(DEFAULT_FEE_RATE),
+            fee_base: self.fee.base.unwrap_or(DEFAULT_FEE_BASE),
+        })
+    }
+
+    /// Registered apps by their keys in spells (`$NAME`).
+    pub fn keyed_apps(&self) -> BTreeMap<String, App> {
+        self.apps
+            .iter()
+            .map(|(name, alias)| (format!("${}", name), alias.app.clone()))
+            .collect()
+    }
+
+    /// Keys in spells (`$NAME`) of the registered apps.
+    pub fn app_keys(&self) -> BTreeMap<App, String> {
+        self.keyed_apps()
+            .into_iter()
+            .map(|(k, app)| (app, k))
+            .collect()
+    }
+
+    /// Paths to the RISC-V binaries of the registered apps.
+    pub fn app_bins(&self) -> BTreeMap<App, PathBuf> {
+        self.apps
+            .values()
+            .filter_map(|alias| alias.bin.clone().map(|bin| (alias.app.clone(), bin)))
+            .collect()
+    }
+}
+
+impl RpcConfig {
+    /// bitcoind RPC URL.
+    pub fn url(&self) -> String {
+        self.url.clone().unwrap_or(DEFAULT_RPC_URL.to_string())
+    }
+
+    /// bitcoind RPC user.
+    pub fn user(&self) -> String {
+        self.user.clone().unwrap_or(DEFAULT_RPC_USER.to_string())
+    }
+
+    /// bitcoind RPC password.
+    pub fn password(&self) -> String {
+        self.password
+            .clone()
+            .unwrap_or(DEFAULT_RPC_PASSWORD.to_string())
+    }
+}
+
+/// Path to the config file: `charms/charms.toml` in the user config directory.
+pub fn config_file() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("charms").join("charms.toml"))
+}
+
+fn override_from_env<T>(
+    setting: &mut Option<T>,
+    var: &str,
+    parse: impl Fn(&str) -> Result<T>,
+) -> Result<()> {
+    if let Ok(s) = env::var(var) {
+        *setting = Some(parse(&s).map_err(|e| anyhow!("{}: {}", var, e))?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config_file() {
+        let config: Config = toml::from_str(
+            r#"
+network = "mainnet"
+
+[fee]
+address = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+rate = 500
+
+[rpc]
+url = "http://localhost:8332"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.network, Some(Network::Mainnet));
+        assert_eq!(config.rpc.url.as_deref(), Some("http://localhost:8332"));
+        assert_eq!(config.prove_api_url(), DEFAULT_PROVE_API_URL);
+        assert_eq!(config.sp1_prover(), "");
+
+        let charms_fee = config.charms_fee().unwrap();
+        assert_eq!(charms_fee.fee_rate, 500);
+        assert_eq!(charms_fee.fee_base, DEFAULT_FEE_BASE);
+
+        assert!(toml::from_str::<Config>("fee_rate = 500").is_err());
+    }
+
+    #[test]
+    fn parse_app_aliases() {
+        let config: Config = toml::from_str(
+            r#"
+[apps.TOAD]
+app = "t/3d7fe7e4cea6121947af73d70e5119bebd8aa5b7edfe74bfaf6e779a1847bd9b/c975d4e0c292fb95efbda5c13312d6ac1d8b5aeff7f0f1e5578645a2da70ff5f"
+bin = "target/toad.elf"
+
+[apps.TOAD.metadata]
+name = "Toad Token"
+ticker = "TOAD"
+decimals = 2
+mascot = "toad"
+"#,
+        )
+        .unwrap();
+
+        let keyed_apps = config.keyed_apps();
+        let app = &keyed_apps["$TOAD"];
+        assert_eq!(app.tag, 't');
+        assert_eq!(config.app_keys()[app], "$TOAD");
+        assert_eq!(config.app_bins()[app], PathBuf::from("target/toad.elf"));
+
+        let metadata = config.apps["TOAD"].metadata.as_ref().unwrap();
+        assert_eq!(metadata.ticker.as_deref(), Some("TOAD"));
+        assert_eq!(metadata.decimals, Some(2));
+        assert_eq!(metadata.other["mascot"].as_str(), Some("toad"));
+    }
+}
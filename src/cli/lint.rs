@@ -0,0 +1,468 @@
+use crate::{
+    cli,
+    cli::{config::Config, SpellLintParams},
+    spell::{Input, Spell},
+    tx,
+};
+use anyhow::{bail, Result};
+use charms_data::App;
+use serde::{
+    de,
+    de::{DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    Serialize,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => f.write_str("error"),
+            Severity::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+/// Problem found in a spell source.
+#[derive(Debug, Serialize)]
+pub struct Issue {
+    pub severity: Severity,
+    pub message: String,
+    /// Path to the offending node, e.g. `outs[1].charms.$01`.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    /// Position (1-based) of the offending node in the spell source.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+/// Lint the spell source: print the issues found, fail if any of them is an error.
+pub fn lint(SpellLintParams { spell, vars, json }: SpellLintParams, config: &Config) -> Result<()> {
+    let source = vars.read_source(&spell)?;
+    let issues = issues(&source, &config.keyed_apps());
+
+    match json {
+        true => cli::print_output(&issues, true)?,
+        false => {
+            for issue in issues.iter() {
+                let location = match (issue.line, issue.column) {
+                    (Some(line), Some(column)) => format!("{}:{}:", line, column),
+                    _ => "".to_string(),
+                };
+                let path = match issue.path.is_empty() {
+                    true => "".to_string(),
+                    false => format!(" ({})", issue.path),
+                };
+                println!(
+                    "{}:{} {}: {}{}",
+                    spell.display(),
+                    location,
+                    issue.severity,
+                    issue.message,
+                    path
+                );
+            }
+        }
+    }
+
+    let errors = issues
+        .iter()
+        .filter(|issue| issue.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        bail!("spell has {} error(s)", errors);
+    }
+    Ok(())
+}
+
+/// Find issues in the spell source: syntax errors, then semantic issues.
+/// Apps in `keyed_apps` (the app alias registry) don't need to be declared in the spell.
+pub(crate) fn issues(source: &str, keyed_apps: &BTreeMap<String, App>) -> Vec<Issue> {
+    // syntax errors and type mismatches: serde_yaml errors have a location
+    if let Err(e) = serde_yaml::from_str::<serde_yaml::Value>(source)
+        .and_then(|_| serde_yaml::from_str::<Spell>(source))
+    {
+        return vec![yaml_error_issue(e)];
+    }
+    let mut spell: Spell = serde_yaml::from_str(source).expect("spell should parse");
+    spell.add_apps(keyed_apps);
+
+    let mut issues: Vec<Issue> = semantic_issues(&spell)
+        .into_iter()
+        .map(|(severity, path, message)| {
+            let (line, column) = locate(source, &path);
+            Issue {
+                severity,
+                message,
+                path: path_string(&path),
+                line,
+                column,
+            }
+        })
+        .collect();
+
+    // anything else the linter doesn't know how to point to
+    if issues.iter().all(|issue| issue.severity != Severity::Error) {
+        if let Err(e) = spell.normalized() {
+            issues.push(Issue {
+                severity: Severity::Error,
+                message: e.to_string(),
+                path: "".to_string(),
+                line: None,
+                column: None,
+            });
+        }
+    }
+
+    issues
+}
+
+fn yaml_error_issue(e: serde_yaml::Error) -> Issue {
+    let (line, column) = match e.location() {
+        Some(location) => (Some(location.line()), Some(location.column())),
+        None => (None, None),
+    };
+    let message = e.to_string();
+    // the location is reported separately
+    let message = match (line, column) {
+        (Some(line), Some(column)) => message
+            .strip_suffix(&format!(" at line {} column {}", line, column))
+            .map(str::to_string)
+            .unwrap_or(message),
+        _ => message,
+    };
+    Issue {
+        severity: Severity::Error,
+        message,
+        path: "".to_string(),
+        line,
+        column,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+impl From<&str> for PathSegment {
+    fn from(key: &str) -> Self {
+        PathSegment::Key(key.to_string())
+    }
+}
+
+impl From<usize> for PathSegment {
+    fn from(index: usize) -> Self {
+        PathSegment::Index(index)
+    }
+}
+
+macro_rules! path {
+    ($($segment:expr),*) => {
+        vec![$(PathSegment::from($segment)),*]
+    };
+}
+
+fn path_string(path: &[PathSegment]) -> String {
+    let mut s = String::new();
+    for segment in path {
+        match segment {
+            PathSegment::Key(key) if s.is_empty() => s.push_str(key),
+            PathSegment::Key(key) => {
+                s.push('.');
+                s.push_str(key);
+            }
+            PathSegment::Index(i) => s.push_str(&format!("[{}]", i)),
+        }
+    }
+    s
+}
+
+type SemanticIssue = (Severity, Vec<PathSegment>, String);
+
+fn semantic_issues(spell: &Spell) -> Vec<SemanticIssue> {
+    let mut issues = vec![];
+
+    let mut app_keys_by_app = BTreeMap::new();
+    for (k, app) in spell.apps.iter() {
+        if let Some(k0) = app_keys_by_app.insert(app, k) {
+            issues.push((
+                Severity::Error,
+                path!["apps", k.as_str()],
+                format!("duplicate app: same as {}", k0),
+            ));
+        }
+    }
+
+    let mut used_app_keys = BTreeSet::new();
+    let mut check_inputs = |issues: &mut Vec<SemanticIssue>, field: &str, inputs: &[Input]| {
+        let mut seen = BTreeMap::new();
+        for (i, input) in inputs.iter().enumerate() {
+            match &input.utxo_id {
+                None => issues.push((
+                    Severity::Error,
+                    path![field, i],
+                    "input has no utxo_id".to_string(),
+                )),
+                Some(utxo_id) => {
+                    if let Some(j) = seen.insert(utxo_id.clone(), i) {
+                        issues.push((
+                            Severity::Error,
+                            path![field, i, "utxo_id"],
+                            format!("duplicate input: same as {}[{}]", field, j),
+                        ));
+                    }
+                }
+            }
+            for k in input.charms.iter().flat_map(|charms| charms.keys()) {
+                used_app_keys.insert(k.clone());
+                if !spell.apps.contains_key(k) {
+                    issues.push((
+                        Severity::Error,
+                        path![field, i, "charms", k.as_str()],
+                        format!("unknown app key {}", k),
+                    ));
+                }
+            }
+        }
+    };
+    check_inputs(&mut issues, "ins", &spell.ins);
+    if let Some(refs) = &spell.refs {
+        check_inputs(&mut issues, "refs", refs);
+    }
+
+    let network = spell.network();
+    for (i, output) in spell.outs.iter().enumerate() {
+        if let Err(e) = tx::output_script_pubkey(output, network) {
+            issues.push((Severity::Error, path!["outs", i], e.to_string()));
+        }
+        if output.sats.is_none() && !output.is_op_return() {
+            issues.push((
+                Severity::Warning,
+                path!["outs", i],
+                "output has no sats: the default amount will be used".to_string(),
+            ));
+        }
+        let Some(charms) = &output.charms else {
+            continue;
+        };
+        if output.is_op_return() && !charms.is_empty() {
+            issues.push((
+                Severity::Error,
+                path!["outs", i, "charms"],
+                "OP_RETURN output can't have charms".to_string(),
+            ));
+        }
+        for k in charms.keys() {
+            used_app_keys.insert(k.clone());
+            if !spell.apps.contains_key(k) {
+                issues.push((
+                    Severity::Error,
+                    path!["outs", i, "charms", k.as_str()],
+                    format!("unknown app key {}", k),
+                ));
+            }
+        }
+    }
+
+    for k in spell.apps.keys().filter(|k| !used_app_keys.contains(*k)) {
+        issues.push((
+            Severity::Warning,
+            path!["apps", k.as_str()],
+            format!("unused app {}: no charms of it in inputs or outputs", k),
+        ));
+    }
+
+    for (field, inputs) in [
+        ("public_inputs", &spell.public_inputs),
+        ("private_inputs", &spell.private_inputs),
+    ] {
+        for k in inputs.iter().flat_map(|inputs| inputs.keys()) {
+            if !spell.apps.contains_key(k) {
+                issues.push((
+                    Severity::Error,
+                    path![field, k.as_str()],
+                    format!("input for undeclared app {}", k),
+                ));
+            }
+        }
+    }
+
+    issues
+}
+
+/// Marker error message: the node at the path is reached.
+const FOUND: &str = "found";
+
+/// Find the position (line, column) of the node at `path` in the spell source.
+/// Deserializes the source down to the node, failing there: serde_yaml reports the location of
+/// the error.
+fn locate(source: &str, path: &[PathSegment]) -> (Option<usize>, Option<usize>) {
+    let Err(e) = Locator(path).deserialize(serde_yaml::Deserializer::from_str(source)) else {
+        return (None, None);
+    };
+    match e.location() {
+        Some(location) => (Some(location.line()), Some(location.column())),
+        None => (None, None),
+    }
+}
+
+struct Locator<'a>(&'a [PathSegment]);
+
+impl<'de> DeserializeSeed<'de> for Locator<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl Locator<'_> {
+    fn found<E: de::Error>(self) -> Result<(), E> {
+        Err(E::custom(FOUND))
+    }
+}
+
+impl<'de> Visitor<'de> for Locator<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("any value")
+    }
+
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<(), E> {
+        self.found()
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<(), E> {
+        self.found()
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<(), E> {
+        self.found()
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<(), E> {
+        self.found()
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<(), E> {
+        self.found()
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        self.found()
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let Some((PathSegment::Index(index), rest)) = self.0.split_first() else {
+            return self.found();
+        };
+        let mut i = 0;
+        loop {
+            let element = match i == *index {
+                true => seq.next_element_seed(Locator(rest))?,
+                false => seq.next_element::<IgnoredAny>()?.map(|_| ()),
+            };
+            if element.is_none() {
+                return Ok(());
+            }
+            i += 1;
+        }
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let Some((PathSegment::Key(key), rest)) = self.0.split_first() else {
+            return self.found();
+        };
+        while let Some(k) = map.next_key::<serde_yaml::Value>()? {
+            match k.as_str() == Some(key) {
+                true => map.next_value_seed(Locator(rest))?,
+                false => map.next_value::<IgnoredAny>().map(|_| ())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn find<'a>(issues: &'a [Issue], path: &str) -> &'a Issue {
+        issues
+            .iter()
+            .find(|issue| issue.path == path)
+            .unwrap_or_else(|| panic!("no issue at {}", path))
+    }
+
+    #[test]
+    fn syntax_error_position() {
+        let found = issues("version: 3\napps: {\nins: []\n", &BTreeMap::new());
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].severity, Severity::Error);
+        assert!(found[0].line.is_some());
+
+        let found = issues(
+            "version: 3\napps: {}\nins: []\nouts:\n  - sats: lots\n",
+            &BTreeMap::new(),
+        );
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].line, found[0].column), (Some(5), Some(11)));
+    }
+
+    #[test]
+    fn semantic_issue_positions() {
+        let source = r#"
+version: 3
+apps:
+  $00: t/0000000000000000000000000000000000000000000000000000000000000000/0000000000000000000000000000000000000000000000000000000000000000
+  $01: n/0000000000000000000000000000000000000000000000000000000000000000/0000000000000000000000000000000000000000000000000000000000000000
+public_inputs:
+  $02: 1
+ins:
+  - utxo_id: f72700ac56bd4dd61f2ccb4acdf21d0b11bb294fc3efa9012b77903932197d2f:2
+    charms:
+      $00: 10
+  - utxo_id: f72700ac56bd4dd61f2ccb4acdf21d0b11bb294fc3efa9012b77903932197d2f:2
+outs:
+  - charms:
+      $00: 10
+      $03: 1
+  - op_return: 00
+"#;
+        let found = issues(source, &BTreeMap::new());
+
+        let issue = find(&found, "ins[1].utxo_id");
+        assert_eq!((issue.line, issue.column), (Some(12), Some(14)));
+        assert_eq!(issue.message, "duplicate input: same as ins[0]");
+
+        let issue = find(&found, "outs[0].charms.$03");
+        assert_eq!((issue.line, issue.column), (Some(16), Some(12)));
+        assert_eq!(issue.severity, Severity::Error);
+
+        let issue = find(&found, "apps.$01");
+        assert_eq!(issue.line, Some(5));
+        assert_eq!(issue.severity, Severity::Warning);
+
+        let issue = find(&found, "public_inputs.$02");
+        assert_eq!(issue.line, Some(7));
+
+        // no destination, no sats
+        let outs_0 = found.iter().filter(|issue| issue.path == "outs[0]");
+        assert_eq!(outs_0.count(), 2);
+        assert!(found.iter().all(|issue| issue.path != "outs[1]"));
+    }
+}
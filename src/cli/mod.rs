@@ -1,6 +1,11 @@
 pub mod app;
+pub mod config;
+pub mod lint;
+pub mod psbt;
 pub mod server;
 pub mod spell;
+pub mod swap;
+pub mod template;
 pub mod tx;
 pub mod wallet;
 
@@ -8,15 +13,18 @@ pub mod wallet;
 use crate::utils::sp1::CudaProver;
 use crate::{
     cli::{
+        config::Config,
         server::Server,
         spell::{Check, Prove, SpellCli},
+        swap::Swap,
         wallet::{List, WalletCli},
     },
-    spell::{CharmsFee, Prover},
+    script::Refund,
+    spell::{Network, Prover},
     utils,
     utils::{BoxedSP1Prover, Shared},
 };
-use bitcoin::{address::NetworkUnchecked, Address};
+use bitcoin::{address::NetworkUnchecked, Address, XOnlyPublicKey};
 use clap::{Args, CommandFactory, Parser, Subcommand};
 use clap_complete::{generate, Shell};
 #[cfg(not(feature = "prover"))]
@@ -24,7 +32,7 @@ use reqwest::Client;
 use serde::Serialize;
 use sp1_sdk::{install::try_install_circuit_artifacts, CpuProver, ProverClient};
 use spell::Cast;
-use std::{io, net::IpAddr, path::PathBuf, str::FromStr, sync::Arc};
+use std::{io, net::IpAddr, path::PathBuf, sync::Arc};
 use utils::AsyncShared;
 
 #[derive(Parser)]
@@ -44,22 +52,30 @@ pub struct ServerConfig {
     #[arg(long, default_value = "17784")]
     port: u16,
 
-    /// bitcoind RPC URL. Set via RPC_URL env var.
-    #[arg(long, env, default_value = "http://localhost:48332")]
+    /// Bitcoin network the server accepts prove requests for (`network` in the config file,
+    /// CHARMS_NETWORK env var). If not set, requests for any network are accepted: requests
+    /// specifying none (in the request or its spell) are for `testnet4`.
+    #[arg(long, value_enum)]
+    network: Option<Network>,
+
+    /// bitcoind RPC URL (`rpc.url` in the config file, RPC_URL env var).
+    /// Defaults to `http://localhost:48332`.
+    #[arg(long)]
     #[cfg(not(feature = "prover"))]
-    rpc_url: String,
+    rpc_url: Option<String>,
 
-    /// bitcoind RPC user. Recommended to set via RPC_USER env var.
-    #[arg(long, env, default_value = "hello")]
+    /// bitcoind RPC user (`rpc.user` in the config file, RPC_USER env var).
+    #[arg(long)]
     #[cfg(not(feature = "prover"))]
-    rpc_user: String,
+    rpc_user: Option<String>,
 
-    /// bitcoind RPC password. Recommended to set via RPC_PASSWORD env var.
+    /// bitcoind RPC password. Recommended to set in the config file (`rpc.password`) or via
+    /// RPC_PASSWORD env var.
     /// Use the .cookie file in the bitcoind data directory to look up the password:
     /// the format is `__cookie__:password`.
-    #[arg(long, env, default_value = "world")]
+    #[arg(long)]
     #[cfg(not(feature = "prover"))]
-    rpc_password: String,
+    rpc_password: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -73,6 +89,18 @@ pub enum Commands {
         command: SpellCommands,
     },
 
+    /// Swap charms for BTC.
+    Swap {
+        #[command(subcommand)]
+        command: SwapCommands,
+    },
+
+    /// Sign and combine PSBTs of spell transactions with inputs owned by multiple parties.
+    Psbt {
+        #[command(subcommand)]
+        command: PsbtCommands,
+    },
+
     /// Work with underlying blockchain transactions.
     Tx {
         #[command(subcommand)]
@@ -111,6 +139,8 @@ pub struct SpellProveParams {
     /// Spell source file (YAML/JSON).
     #[arg(long, default_value = "/dev/stdin")]
     spell: PathBuf,
+    #[command(flatten)]
+    vars: SpellVars,
 
     /// Pre-requisite transactions (hex-encoded) separated by commas (`,`).
     /// These are the transactions that create the UTXOs that the `tx` (and the spell) spends.
@@ -138,6 +168,19 @@ pub struct SpellProveParams {
     /// Fee rate in sats/vB.
     #[arg(long, default_value = "2.0")]
     fee_rate: f64,
+
+    /// Bitcoin network: addresses are validated against it (`network` in the config file,
+    /// CHARMS_NETWORK env var). Defaults to the spell's network or `testnet4`.
+    #[arg(long, value_enum)]
+    network: Option<Network>,
+
+    #[command(flatten)]
+    refund: RefundParams,
+
+    /// Output PSBTs (base64-encoded) instead of transactions: for the inputs to be signed by
+    /// multiple parties (`psbt sign`, `psbt combine`, `psbt finalize`).
+    #[arg(long)]
+    psbt: bool,
 }
 
 #[derive(Args)]
@@ -145,9 +188,52 @@ pub struct SpellCheckParams {
     /// Path to spell source file (YAML/JSON).
     #[arg(long, default_value = "/dev/stdin")]
     spell: PathBuf,
+    #[command(flatten)]
+    vars: SpellVars,
     /// Path to the apps' RISC-V binaries.
     #[arg(long, value_delimiter = ',')]
     app_bins: Vec<PathBuf>,
+
+    /// Bitcoin network: addresses are validated against it (`network` in the config file,
+    /// CHARMS_NETWORK env var). Defaults to the spell's network or `testnet4`.
+    #[arg(long, value_enum)]
+    network: Option<Network>,
+}
+
+/// Variables to substitute for `${key}` placeholders in the spell source.
+#[derive(Args)]
+pub struct SpellVars {
+    /// Variable value (`key=value`) to substitute for `${key}` in the spell source.
+    /// Can be repeated. Overrides values from `--vars`.
+    #[arg(long, value_name = "KEY=VALUE", value_parser = template::parse_var)]
+    var: Vec<(String, String)>,
+    /// File (YAML/JSON) with variable values: a map of `key: value`.
+    #[arg(long, value_name = "FILE")]
+    vars: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct SpellRenderParams {
+    /// Path to spell source file (YAML/JSON).
+    #[arg(long, default_value = "/dev/stdin")]
+    spell: PathBuf,
+    #[command(flatten)]
+    vars: SpellVars,
+    /// Output in JSON format (default is YAML).
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args)]
+pub struct SpellLintParams {
+    /// Path to spell source file (YAML/JSON).
+    #[arg(long, default_value = "/dev/stdin")]
+    spell: PathBuf,
+    #[command(flatten)]
+    vars: SpellVars,
+    /// Output in JSON format (default is one line per issue).
+    #[arg(long)]
+    json: bool,
 }
 
 #[derive(Subcommand)]
@@ -161,6 +247,100 @@ pub enum SpellCommands {
     /// commit transaction. Signs both the commit and spell transactions with the user's wallet.
     /// Returns the hex-encoded signed commit and spell transactions.
     Cast(#[command(flatten)] SpellCastParams),
+    /// Render the spell: substitute the variables in the spell source and print the spell.
+    Render(#[command(flatten)] SpellRenderParams),
+    /// Lint the spell source: report syntax errors and semantic issues with their positions.
+    Lint(#[command(flatten)] SpellLintParams),
+    /// Print the JSON Schema of the spell source format.
+    Schema,
+}
+
+#[derive(Args)]
+pub struct SwapOfferParams {
+    /// UTXO with the charms to offer (`txid:vout`).
+    #[arg(long)]
+    utxo: String,
+    /// Price of the charms in sats.
+    #[arg(long)]
+    price: u64,
+    /// Address to receive the payment to. Defaults to a new address of the wallet.
+    #[arg(long)]
+    address: Option<Address<NetworkUnchecked>>,
+
+    /// Bitcoin network: addresses are validated against it (`network` in the config file,
+    /// CHARMS_NETWORK env var). Defaults to `testnet4`.
+    #[arg(long, value_enum)]
+    network: Option<Network>,
+}
+
+#[derive(Args)]
+pub struct SwapTakeParams {
+    /// Offer (hex-encoded transaction) created by `swap offer`.
+    #[arg(long)]
+    offer: String,
+    /// Address to send the charms to. Defaults to a new address of the wallet.
+    #[arg(long)]
+    address: Option<Address<NetworkUnchecked>>,
+    /// Path to the apps' RISC-V binaries.
+    #[arg(long, value_delimiter = ',')]
+    app_bins: Vec<PathBuf>,
+    /// Funding UTXO ID (`txid:vout`): pays the price and the fees.
+    #[arg(long, alias = "funding-utxo-id")]
+    funding_utxo: String,
+    /// Fee rate in sats/vB.
+    #[arg(long, default_value = "2.0")]
+    fee_rate: f64,
+
+    /// Bitcoin network: addresses are validated against it (`network` in the config file,
+    /// CHARMS_NETWORK env var). Defaults to `testnet4`.
+    #[arg(long, value_enum)]
+    network: Option<Network>,
+}
+
+#[derive(Subcommand)]
+pub enum SwapCommands {
+    /// Offer charms in a UTXO for a price in sats.
+    /// Signs the UTXO (`SIGHASH_SINGLE|ANYONECANPAY`) with the user's wallet, against the payment
+    /// output. Returns the hex-encoded offer transaction (not to be broadcast by itself).
+    Offer(#[command(flatten)] SwapOfferParams),
+    /// Take an offer: pay the price and receive the charms.
+    /// Creates and proves the spell completing the offer, funded by the user's wallet.
+    /// Returns the hex-encoded signed commit and spell transactions.
+    Take(#[command(flatten)] SwapTakeParams),
+}
+
+#[derive(Subcommand)]
+pub enum PsbtCommands {
+    /// Sign the inputs the user's wallet owns. Returns the updated PSBT (base64-encoded).
+    /// The spell proof is not affected: parties can sign in any order.
+    Sign {
+        /// PSBT (base64-encoded), e.g. produced by `spell prove --psbt`.
+        #[arg(long)]
+        psbt: String,
+
+        /// Bitcoin network (`network` in the config file, CHARMS_NETWORK env var).
+        /// Passed to `bitcoin-cli` as `-chain`.
+        #[arg(long, value_enum)]
+        network: Option<Network>,
+    },
+    /// Combine PSBTs of the same transaction signed by different parties.
+    /// Returns the combined PSBT (base64-encoded).
+    Combine {
+        /// PSBTs (base64-encoded) separated by commas (`,`).
+        #[arg(long, value_delimiter = ',', required = true)]
+        psbts: Vec<String>,
+    },
+    /// Finalize a fully signed PSBT. Returns the hex-encoded transaction, ready to broadcast.
+    Finalize {
+        /// PSBT (base64-encoded).
+        #[arg(long)]
+        psbt: String,
+
+        /// Bitcoin network (`network` in the config file, CHARMS_NETWORK env var).
+        /// Passed to `bitcoin-cli` as `-chain`.
+        #[arg(long, value_enum)]
+        network: Option<Network>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -175,6 +355,77 @@ pub enum TxCommands {
         #[arg(long)]
         json: bool,
     },
+    /// Trace the provenance of the charms in a UTXO: the spell transactions they came through,
+    /// back to the transactions minting them.
+    Trace(#[command(flatten)] TxTraceParams),
+    /// Refund the committed spell output of a spell transaction that never confirmed, via its
+    /// refund path. Returns the PSBT (base64-encoded) to sign with the refund key (`psbt sign`).
+    Refund(#[command(flatten)] TxRefundParams),
+}
+
+#[derive(Args)]
+pub struct TxRefundParams {
+    /// Commit transaction (hex-encoded): creates the committed spell output.
+    #[arg(long)]
+    commit_tx: String,
+    /// Spell transaction (hex-encoded) spending the committed spell output.
+    #[arg(long)]
+    spell_tx: String,
+
+    /// Refund key (x-only, hex-encoded) the committed spell output was created with
+    /// (`--refund-pubkey` of `spell prove` or `spell cast`).
+    #[arg(long)]
+    refund_pubkey: XOnlyPublicKey,
+    /// Relative timelock (in blocks) of the refund path.
+    #[arg(long, default_value = "144")]
+    refund_timeout: u16,
+
+    /// Address to send the refund to. Defaults to a new address of the wallet.
+    #[arg(long)]
+    address: Option<Address<NetworkUnchecked>>,
+    /// Fee rate in sats/vB.
+    #[arg(long, default_value = "2.0")]
+    fee_rate: f64,
+
+    /// Bitcoin network: addresses are validated against it (`network` in the config file,
+    /// CHARMS_NETWORK env var). Defaults to `testnet4`.
+    #[arg(long, value_enum)]
+    network: Option<Network>,
+}
+
+/// Source of the transactions `tx trace` walks through.
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum TxSource {
+    /// `bitcoin-cli getrawtransaction` (the node needs `-txindex` to find spent outputs).
+    #[default]
+    BitcoinCli,
+    /// bitcoind RPC (`rpc` settings in the config file, RPC_URL, RPC_USER and RPC_PASSWORD env
+    /// vars).
+    Rpc,
+}
+
+#[derive(Args)]
+pub struct TxTraceParams {
+    /// UTXO to trace (txid:vout).
+    utxo: String,
+
+    /// Maximum number of transactions to walk back from the one creating the UTXO.
+    /// No limit by default.
+    #[arg(long)]
+    depth: Option<usize>,
+
+    /// Where to fetch the transactions from.
+    #[arg(long, value_enum, default_value_t)]
+    source: TxSource,
+
+    /// Bitcoin network (`network` in the config file, CHARMS_NETWORK env var).
+    /// Passed to `bitcoin-cli` as `-chain`.
+    #[arg(long, value_enum)]
+    network: Option<Network>,
+
+    /// Output in JSON format (default is YAML).
+    #[arg(long)]
+    json: bool,
 }
 
 #[derive(Subcommand)]
@@ -223,15 +474,53 @@ pub struct SpellCastParams {
     /// Path to spell source file (YAML/JSON).
     #[arg(long, default_value = "/dev/stdin")]
     spell: PathBuf,
+    #[command(flatten)]
+    vars: SpellVars,
+    /// Paths to a chain of spell source files (comma-separated), to be cast in order instead of
+    /// `--spell`. Later spells may spend outputs of earlier ones: `${chain.N.txid}` is the
+    /// transaction ID of the N-th (from 0) spell of the chain. All transactions are printed in
+    /// order, to be broadcast together.
+    #[arg(long, value_delimiter = ',', conflicts_with = "spell")]
+    chain: Vec<PathBuf>,
     /// Path to the apps' RISC-V binaries.
     #[arg(long, value_delimiter = ',')]
     app_bins: Vec<PathBuf>,
-    /// Funding UTXO ID (`txid:vout`).
+    /// Funding UTXO ID (`txid:vout`). With `--chain`, funds the first spell: each next spell is
+    /// funded by the change output of the previous one.
     #[arg(long, alias = "funding-utxo-id")]
     funding_utxo: String,
     /// Fee rate in sats/vB.
     #[arg(long, default_value = "2.0")]
     fee_rate: f64,
+
+    /// Bitcoin network: addresses are validated against it (`network` in the config file,
+    /// CHARMS_NETWORK env var). Defaults to the spell's network or `testnet4`.
+    #[arg(long, value_enum)]
+    network: Option<Network>,
+
+    #[command(flatten)]
+    refund: RefundParams,
+}
+
+#[derive(Args)]
+pub struct RefundParams {
+    /// Key (x-only, hex-encoded) to refund the committed spell output to, if the spell
+    /// transaction never confirms. The committed spell output gets a refund path only if set.
+    #[arg(long)]
+    refund_pubkey: Option<XOnlyPublicKey>,
+
+    /// Relative timelock (in blocks) of the refund path.
+    #[arg(long, default_value = "144")]
+    refund_timeout: u16,
+}
+
+impl RefundParams {
+    fn refund(&self) -> Option<Refund> {
+        self.refund_pubkey.map(|pubkey| Refund {
+            pubkey,
+            timeout: self.refund_timeout,
+        })
+    }
 }
 
 #[derive(Subcommand)]
@@ -245,21 +534,64 @@ pub async fn run() -> anyhow::Result<()> {
 
     let cli = Cli::parse();
 
+    // commands that don't need the config don't fail on a broken config file
     match cli.command {
         Commands::Server(server_config) => {
-            let server = server(server_config);
+            let server = server(server_config, Config::load()?);
             server.serve().await
         }
-        Commands::Spell { command } => {
-            let spell_cli = spell_cli();
-            match command {
-                SpellCommands::Check(params) => spell_cli.check(params),
-                SpellCommands::Prove(params) => spell_cli.prove(params).await,
-                SpellCommands::Cast(params) => spell_cli.cast(params).await,
+        Commands::Spell { command } => match command {
+            SpellCommands::Check(mut params) => {
+                let config = Config::load()?;
+                params.network = params.network.or(config.network);
+                spell_cli(&config).check(params)
             }
-        }
+            SpellCommands::Prove(mut params) => {
+                let config = Config::load()?;
+                params.network = params.network.or(config.network);
+                spell_cli(&config).prove(params).await
+            }
+            SpellCommands::Cast(mut params) => {
+                let config = Config::load()?;
+                params.network = params.network.or(config.network);
+                spell_cli(&config).cast(params).await
+            }
+            SpellCommands::Render(params) => spell::render(params, &Config::load()?),
+            SpellCommands::Lint(params) => lint::lint(params, &Config::load()?),
+            SpellCommands::Schema => spell::schema(),
+        },
+        Commands::Swap { command } => match command {
+            SwapCommands::Offer(mut params) => {
+                let config = Config::load()?;
+                params.network = params.network.or(config.network);
+                spell_cli(&config).offer(params)
+            }
+            SwapCommands::Take(mut params) => {
+                let config = Config::load()?;
+                params.network = params.network.or(config.network);
+                spell_cli(&config).take(params).await
+            }
+        },
+        Commands::Psbt { command } => match command {
+            PsbtCommands::Sign { psbt, network } => {
+                psbt::sign(psbt, network.or(Config::load()?.network))
+            }
+            PsbtCommands::Combine { psbts } => psbt::combine(psbts),
+            PsbtCommands::Finalize { psbt, network } => {
+                psbt::finalize(psbt, network.or(Config::load()?.network))
+            }
+        },
         Commands::Tx { command } => match command {
-            TxCommands::ShowSpell { tx, json } => tx::tx_show_spell(tx, json),
+            TxCommands::ShowSpell { tx, json } => tx::tx_show_spell(tx, json, &Config::load()?),
+            TxCommands::Trace(mut params) => {
+                let config = Config::load()?;
+                params.network = params.network.or(config.network);
+                tx::tx_trace(params, &config)
+            }
+            TxCommands::Refund(mut params) => {
+                params.network = params.network.or(Config::load()?.network);
+                tx::tx_refund(params)
+            }
         },
         Commands::App { command } => match command {
             AppCommands::New { name } => app::new(&name),
@@ -268,7 +600,7 @@ pub async fn run() -> anyhow::Result<()> {
             AppCommands::Run { spell, path } => app::run(spell, path),
         },
         Commands::Wallet { command } => {
-            let wallet_cli = wallet_cli();
+            let wallet_cli = wallet_cli(&Config::load()?);
             match command {
                 WalletCommands::List(params) => wallet_cli.list(params),
             }
@@ -283,24 +615,34 @@ pub async fn run() -> anyhow::Result<()> {
     }
 }
 
-fn server(server_config: ServerConfig) -> Server {
-    let prover = AsyncShared::new(spell_prover);
-    Server::new(server_config, prover)
+fn server(server_config: ServerConfig, config: Config) -> Server {
+    let mut config = config;
+    config.network = server_config.network.or(config.network);
+    #[cfg(not(feature = "prover"))]
+    {
+        config.rpc.url = server_config.rpc_url.clone().or(config.rpc.url);
+        config.rpc.user = server_config.rpc_user.clone().or(config.rpc.user);
+        config.rpc.password = server_config.rpc_password.clone().or(config.rpc.password);
+    }
+
+    let prover_config = config.clone();
+    let prover = AsyncShared::new(move || spell_prover(&prover_config));
+    Server::new(server_config, &config, prover)
 }
 
-#[tracing::instrument(level = "debug")]
-fn spell_prover() -> Prover {
+#[tracing::instrument(level = "debug", skip(config))]
+fn spell_prover(config: &Config) -> Prover {
+    let sp1_prover = config.sp1_prover().to_string();
+    let app_sp1_prover = sp1_prover.clone();
     let app_prover = Arc::new(app::Prover {
-        sp1_client: Arc::new(Shared::new(app_sp1_client)),
+        sp1_client: Arc::new(Shared::new(move || app_sp1_client(&app_sp1_prover))),
     });
 
-    let spell_sp1_client = spell_sp1_client(&app_prover.sp1_client);
+    let spell_sp1_client = spell_sp1_client(sp1_prover, &app_prover.sp1_client);
 
-    let charms_fee_settings = charms_fee_settings();
+    let charms_fee_settings = config.charms_fee();
 
-    let charms_prove_api_url = std::env::var("CHARMS_PROVE_API_URL")
-        .ok()
-        .unwrap_or("https://prove.charms.dev/spells/prove".to_string());
+    let charms_prove_api_url = config.prove_api_url();
 
     #[cfg(not(feature = "prover"))]
     let client = Client::builder()
@@ -322,66 +664,33 @@ fn spell_prover() -> Prover {
     spell_prover
 }
 
-fn charms_fee_settings() -> Option<CharmsFee> {
-    charms_fee_address().map(|fee_address| {
-        let charms_fee_rate = charms_fee_rate();
-        let charms_fee_base = charms_fee_base();
-        CharmsFee {
-            fee_address,
-            fee_rate: charms_fee_rate,
-            fee_base: charms_fee_base,
-        }
-    })
-}
-
-fn charms_fee_address() -> Option<Address<NetworkUnchecked>> {
-    std::env::var("CHARMS_FEE_ADDRESS")
-        .ok()
-        .map(|s| Address::from_str(&s).expect("CHARMS_FEE_ADDRESS must be a valid Bitcoin address"))
-}
-
-fn charms_fee_rate() -> u64 {
-    std::env::var("CHARMS_FEE_RATE")
-        .ok()
-        .map(|s| {
-            s.parse::<u64>()
-                .expect("CHARMS_FEE_RATE must be an unsigned integer")
-        })
-        .unwrap_or(500)
-}
-
-fn charms_fee_base() -> u64 {
-    std::env::var("CHARMS_FEE_BASE")
-        .ok()
-        .map(|s| {
-            s.parse::<u64>()
-                .expect("CHARMS_FEE_BASE must be an unsigned integer")
-        })
-        .unwrap_or(1000)
-}
-
-fn spell_cli() -> SpellCli {
-    let spell_prover = spell_prover();
+fn spell_cli(config: &Config) -> SpellCli {
+    let spell_prover = spell_prover(config);
 
     let spell_cli = SpellCli {
         app_prover: spell_prover.app_prover.clone(),
         spell_prover: Arc::new(spell_prover),
+        keyed_apps: config.keyed_apps(),
+        app_bins: config.app_bins(),
     };
     spell_cli
 }
 
-fn app_sp1_client() -> BoxedSP1Prover {
-    match std::env::var("SP1_PROVER").unwrap_or_default().as_str() {
+fn app_sp1_client(sp1_prover: &str) -> BoxedSP1Prover {
+    match sp1_prover {
         "network" => Box::new(sp1_cpu_client()),
-        "" | "cpu" | "cuda" => sp1_env_client(),
+        "" | "cpu" | "cuda" => sp1_client(sp1_prover),
         _ => unreachable!("Only 'cpu', 'cuda', and 'network' are supported as SP1_PROVER values"),
     }
 }
 
-fn spell_sp1_client(app_sp1_client: &Arc<Shared<BoxedSP1Prover>>) -> Arc<Shared<BoxedSP1Prover>> {
-    match std::env::var("SP1_PROVER").unwrap_or_default().as_str() {
+fn spell_sp1_client(
+    sp1_prover: String,
+    app_sp1_client: &Arc<Shared<BoxedSP1Prover>>,
+) -> Arc<Shared<BoxedSP1Prover>> {
+    match sp1_prover.as_str() {
         "" | "cpu" | "cuda" => app_sp1_client.clone(),
-        "network" => Arc::new(Shared::new(sp1_env_client)),
+        "network" => Arc::new(Shared::new(move || sp1_client(&sp1_prover))),
         _ => unreachable!("Only 'cpu', 'cuda', and 'network' are supported as SP1_PROVER values"),
     }
 }
@@ -397,17 +706,24 @@ pub fn sp1_cpu_client() -> CpuProver {
     ProverClient::builder().cpu().build()
 }
 
+/// SP1 prover client for `sp1_prover` (`cpu`, `cuda` or `network`; `cpu` if empty).
 #[tracing::instrument(level = "debug")]
-fn sp1_env_client() -> BoxedSP1Prover {
-    match std::env::var("SP1_PROVER").unwrap_or_default().as_str() {
+fn sp1_client(sp1_prover: &str) -> BoxedSP1Prover {
+    match sp1_prover {
         #[cfg(feature = "prover")]
         "cuda" => Box::new(charms_sp1_cuda_client()),
-        _ => Box::new(ProverClient::from_env()),
+        #[cfg(not(feature = "prover"))]
+        "cuda" => Box::new(ProverClient::builder().cuda().build()),
+        "network" => Box::new(ProverClient::builder().network().build()),
+        _ => Box::new(sp1_cpu_client()),
     }
 }
 
-fn wallet_cli() -> WalletCli {
-    let wallet_cli = WalletCli {};
+fn wallet_cli(config: &Config) -> WalletCli {
+    let wallet_cli = WalletCli {
+        network: config.network,
+        app_keys: config.app_keys(),
+    };
     wallet_cli
 }
 
@@ -0,0 +1,75 @@
+use crate::{cli::wallet::bitcoin_cli, spell::Network};
+use anyhow::{anyhow, ensure, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bitcoin::Psbt;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+struct BProcessedPsbt {
+    psbt: Option<String>,
+    hex: Option<String>,
+    complete: bool,
+}
+
+pub(crate) fn parse_psbt(s: &str) -> Result<Psbt> {
+    Ok(Psbt::deserialize(&BASE64_STANDARD.decode(s.trim())?)?)
+}
+
+pub(crate) fn psbt_base64(psbt: &Psbt) -> String {
+    BASE64_STANDARD.encode(psbt.serialize())
+}
+
+/// Sign the PSBT inputs the wallet can sign. Print the updated PSBT (base64-encoded).
+pub fn sign(psbt: String, network: Option<Network>) -> Result<()> {
+    // parse first: to fail fast
+    let psbt = psbt_base64(&parse_psbt(&psbt)?);
+    // sign, don't finalize: other parties might need to sign the same inputs
+    let processed = bitcoin_cli_psbt(
+        network,
+        &[
+            "walletprocesspsbt",
+            &psbt,
+            "true",
+            "DEFAULT",
+            "true",
+            "false",
+        ],
+    )?;
+    let psbt = processed
+        .psbt
+        .ok_or_else(|| anyhow!("walletprocesspsbt did not return a PSBT"))?;
+    println!("{}", psbt);
+    Ok(())
+}
+
+/// Combine signatures (and other data) from PSBTs for the same transaction. Print the combined
+/// PSBT (base64-encoded).
+pub fn combine(psbts: Vec<String>) -> Result<()> {
+    ensure!(!psbts.is_empty(), "no PSBTs to combine");
+    let mut psbts = psbts.iter().map(|s| parse_psbt(s));
+    let mut combined = psbts.next().expect("there should be at least one PSBT")?;
+    for psbt in psbts {
+        combined.combine(psbt?)?;
+    }
+    println!("{}", psbt_base64(&combined));
+    Ok(())
+}
+
+/// Finalize the PSBT. Print the hex-encoded transaction, ready to broadcast.
+pub fn finalize(psbt: String, network: Option<Network>) -> Result<()> {
+    let psbt = psbt_base64(&parse_psbt(&psbt)?);
+    let processed = bitcoin_cli_psbt(network, &["finalizepsbt", &psbt])?;
+    ensure!(
+        processed.complete,
+        "PSBT is not complete: not all inputs are signed"
+    );
+    let tx_hex = processed
+        .hex
+        .ok_or_else(|| anyhow!("finalizepsbt did not return a transaction"))?;
+    println!("{}", tx_hex);
+    Ok(())
+}
+
+fn bitcoin_cli_psbt(network: Option<Network>, args: &[&str]) -> Result<BProcessedPsbt> {
+    Ok(serde_json::from_slice(&bitcoin_cli(network, args)?)?)
+}
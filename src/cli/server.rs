@@ -1,34 +1,42 @@
 use crate::{
-    cli::ServerConfig,
-    spell::{ProveRequest, ProveSpellTx, Prover},
+    cli::{config::Config, ServerConfig},
+    spell::{resolve_network, Network, ProveRequest, ProveSpellTx, Prover},
     utils::AsyncShared,
 };
 #[cfg(not(feature = "prover"))]
-use crate::{spell::Spell, tx::norm_spell};
+use crate::{spell::Spell, SPELL_VK};
 use anyhow::Result;
+#[cfg(not(feature = "prover"))]
+use axum::{extract::Path, routing::put};
 use axum::{
     extract::State,
-    http::{StatusCode},
+    http::StatusCode,
     routing::{get, post},
     Json, Router,
 };
 #[cfg(not(feature = "prover"))]
-use axum::{extract::Path, routing::put};
-#[cfg(not(feature = "prover"))]
 use bitcoin::consensus::encode::deserialize_hex;
 use bitcoin::consensus::encode::serialize_hex;
 #[cfg(not(feature = "prover"))]
 use bitcoincore_rpc::{jsonrpc::Error::Rpc, Auth, Client, RpcApi};
+#[cfg(not(feature = "prover"))]
+use charms_client::{tx::extract_and_verify_spell, SpellError};
+#[cfg(not(feature = "prover"))]
+use charms_data::App;
 use serde::{Deserialize, Serialize};
 #[cfg(not(feature = "prover"))]
-use std::str::FromStr;
+use std::{collections::BTreeMap, str::FromStr};
 use std::{sync::Arc, time::Duration};
 use tower_http::cors::{Any, CorsLayer};
 
 pub struct Server {
     pub config: ServerConfig,
+    pub network: Option<Network>,
     #[cfg(not(feature = "prover"))]
     pub rpc: Arc<Client>,
+    /// Keys of the registered apps in the spells shown.
+    #[cfg(not(feature = "prover"))]
+    pub app_keys: Arc<BTreeMap<App, String>>,
     pub prover: Arc<AsyncShared<Prover>>,
 }
 
@@ -56,18 +64,21 @@ fn cors_layer() -> CorsLayer {
 }
 
 impl Server {
-    pub fn new(config: ServerConfig, prover: AsyncShared<Prover>) -> Self {
+    pub fn new(config: ServerConfig, charms_config: &Config, prover: AsyncShared<Prover>) -> Self {
         #[cfg(not(feature = "prover"))]
         let rpc = Arc::new(bitcoind_client(
-            config.rpc_url.clone(),
-            config.rpc_user.clone(),
-            config.rpc_password.clone(),
+            charms_config.rpc.url(),
+            charms_config.rpc.user(),
+            charms_config.rpc.password(),
         ));
         let prover = Arc::new(prover);
         Self {
             config,
+            network: charms_config.network,
             #[cfg(not(feature = "prover"))]
             rpc,
+            #[cfg(not(feature = "prover"))]
+            app_keys: Arc::new(charms_config.app_keys()),
             prover,
         }
     }
@@ -80,11 +91,12 @@ impl Server {
         #[cfg(not(feature = "prover"))]
         let app = app
             .route("/spells/{txid}", get(show_spell_by_txid))
-            .with_state(self.rpc.clone())
-            .route("/spells/{txid}", put(show_spell_for_tx_hex));
+            .route("/spells/{txid}", put(show_spell_for_tx_hex))
+            .with_state((self.rpc.clone(), self.app_keys.clone()));
         let app = app
             .route("/spells/prove", post(prove_spell))
-            .with_state(self.prover.clone())
+            .route("/spells/prove/batch", post(prove_spells))
+            .with_state((self.prover.clone(), self.network))
             .route("/ready", get(|| async { "OK" }))
             .layer(cors_layer());
 
@@ -102,33 +114,84 @@ impl Server {
 #[cfg(not(feature = "prover"))]
 #[tracing::instrument(level = "debug", skip_all)]
 async fn show_spell_by_txid(
-    State(rpc): State<Arc<Client>>,
+    State((rpc, app_keys)): State<(Arc<Client>, Arc<BTreeMap<App, String>>)>,
     Path(txid): Path<String>,
-) -> Result<Json<Spell>, StatusCode> {
-    get_spell(rpc, &txid).map(Json)
+) -> Result<Json<Spell>, (StatusCode, String)> {
+    get_spell(rpc, &txid, &app_keys).map(Json)
 }
 
 #[cfg(not(feature = "prover"))]
 #[tracing::instrument(level = "debug", skip_all)]
 async fn show_spell_for_tx_hex(
+    State((_, app_keys)): State<(Arc<Client>, Arc<BTreeMap<App, String>>)>,
     Path(txid): Path<String>,
     Json(payload): Json<ShowSpellRequest>,
-) -> Result<Json<Spell>, StatusCode> {
-    show_spell(&txid, &payload).map(Json)
+) -> Result<Json<Spell>, (StatusCode, String)> {
+    show_spell(&txid, &payload, &app_keys).map(Json)
+}
+
+/// Set the network of a prove request, making sure it agrees with the server's (if set).
+/// Requests specifying no network (and served by a server with none) are for `testnet4`.
+fn with_network(
+    payload: ProveRequest,
+    network: Option<Network>,
+) -> Result<ProveRequest, (StatusCode, String)> {
+    let mut payload = payload;
+    payload.network = Some(
+        resolve_network(&[
+            ("server", network),
+            ("request", payload.network),
+            ("spell", payload.spell.network),
+        ])
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?,
+    );
+    Ok(payload)
 }
 
 #[tracing::instrument(level = "debug", skip_all)]
 async fn prove_spell(
-    State(prover): State<Arc<AsyncShared<Prover>>>,
+    State((prover, network)): State<(Arc<AsyncShared<Prover>>, Option<Network>)>,
     Json(payload): Json<ProveRequest>,
-) -> Result<Json<[String; 2]>, StatusCode> {
+) -> Result<Json<[String; 2]>, (StatusCode, String)> {
+    let payload = with_network(payload, network)?;
     let result = prover
         .get()
         .await
         .prove_spell_tx(payload)
         .await
         .map(|[tx0, tx1]| [serialize_hex(&tx0), serialize_hex(&tx1)])
-        .map_err(|_| StatusCode::BAD_REQUEST)?;
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(Json(result))
+}
+
+/// `POST /spells/prove/batch`: prove a batch of independent spells (a JSON array of prove
+/// requests, as for `/spells/prove`). Returns the `[commit_tx, spell_tx]` pair (hex-encoded) of
+/// each spell, in order.
+///
+/// Each spell still gets its own Groth16 proof: batching only saves setting up the spell checker
+/// and the apps for every spell. If any spell fails, the whole batch fails (with the index of the
+/// spell in the error) and no transactions are returned.
+#[tracing::instrument(level = "debug", skip_all)]
+async fn prove_spells(
+    State((prover, network)): State<(Arc<AsyncShared<Prover>>, Option<Network>)>,
+    Json(payload): Json<Vec<ProveRequest>>,
+) -> Result<Json<Vec<[String; 2]>>, (StatusCode, String)> {
+    let payload = payload
+        .into_iter()
+        .map(|payload| with_network(payload, network))
+        .collect::<Result<Vec<_>, _>>()?;
+    let result = prover
+        .get()
+        .await
+        .prove_spell_txs(payload)
+        .await
+        .map(|tx_pairs| {
+            tx_pairs
+                .iter()
+                .map(|[tx0, tx1]| [serialize_hex(tx0), serialize_hex(tx1)])
+                .collect()
+        })
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
     Ok(Json(result))
 }
 
@@ -142,38 +205,115 @@ fn bitcoind_client(rpc_url: String, rpc_user: String, rpc_password: String) -> C
 }
 
 #[cfg(not(feature = "prover"))]
-fn get_spell(rpc: Arc<Client>, txid: &str) -> Result<Spell, StatusCode> {
-    let txid = bitcoin::Txid::from_str(txid).map_err(|_| StatusCode::BAD_REQUEST)?;
+fn get_spell(
+    rpc: Arc<Client>,
+    txid: &str,
+    app_keys: &BTreeMap<App, String>,
+) -> Result<Spell, (StatusCode, String)> {
+    let txid =
+        bitcoin::Txid::from_str(txid).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
 
     match rpc.get_raw_transaction(&txid, None) {
-        Ok(tx) => extract_spell(&tx),
+        Ok(tx) => extract_spell(&tx, app_keys),
         Err(e) => match e {
             bitcoincore_rpc::Error::JsonRpc(Rpc(rpc_error)) if rpc_error.code == -5 => {
-                Err(StatusCode::NOT_FOUND)
+                Err((StatusCode::NOT_FOUND, rpc_error.message))
             }
             _ => {
                 tracing::warn!("Error: {:?}", e);
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
+                Err((StatusCode::INTERNAL_SERVER_ERROR, String::new()))
             }
         },
     }
 }
 
 #[cfg(not(feature = "prover"))]
-fn show_spell(txid: &str, request: &ShowSpellRequest) -> Result<Spell, StatusCode> {
-    let txid = bitcoin::Txid::from_str(txid).map_err(|_| StatusCode::BAD_REQUEST)?;
+fn show_spell(
+    txid: &str,
+    request: &ShowSpellRequest,
+    app_keys: &BTreeMap<App, String>,
+) -> Result<Spell, (StatusCode, String)> {
+    let txid =
+        bitcoin::Txid::from_str(txid).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
     let tx: bitcoin::Transaction =
-        deserialize_hex(&request.tx_hex).map_err(|_| StatusCode::BAD_REQUEST)?;
+        deserialize_hex(&request.tx_hex).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
     if tx.compute_txid() != txid {
-        return Err(StatusCode::BAD_REQUEST);
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("transaction ID is {}, not {}", tx.compute_txid(), txid),
+        ));
     }
-    extract_spell(&tx)
+    extract_spell(&tx, app_keys)
 }
 
 #[cfg(not(feature = "prover"))]
-fn extract_spell(tx: &bitcoin::Transaction) -> Result<Spell, StatusCode> {
-    match norm_spell(&tx) {
-        None => Err(StatusCode::NO_CONTENT),
-        Some(spell) => Ok(Spell::denormalized(&spell)),
+fn extract_spell(
+    tx: &bitcoin::Transaction,
+    app_keys: &BTreeMap<App, String>,
+) -> Result<Spell, (StatusCode, String)> {
+    match extract_and_verify_spell(tx, SPELL_VK) {
+        Ok(spell) => Ok(Spell::denormalized(&spell, app_keys)),
+        Err(SpellError::NoSpell) => Err((StatusCode::NO_CONTENT, String::new())),
+        Err(e) => Err((StatusCode::UNPROCESSABLE_ENTITY, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::OutPoint;
+
+    fn prove_request(network: Option<Network>, spell_network: Option<Network>) -> ProveRequest {
+        let mut spell = crate::spell::Spell::new();
+        spell.network = spell_network;
+        ProveRequest {
+            spell,
+            binaries: Default::default(),
+            prev_txs: vec![],
+            funding_utxo: OutPoint::null(),
+            funding_utxo_value: 10000,
+            change_address: "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+                .parse()
+                .unwrap(),
+            fee_rate: 2.0,
+            charms_fee: None,
+            network,
+            refund: None,
+        }
+    }
+
+    #[test]
+    fn with_network_defaults_to_testnet4() {
+        let payload = with_network(prove_request(None, None), None).unwrap();
+        assert_eq!(payload.network, Some(Network::Testnet4));
+    }
+
+    #[test]
+    fn with_network_accepts_any_network_if_server_has_none() {
+        let payload = with_network(prove_request(Some(Network::Regtest), None), None).unwrap();
+        assert_eq!(payload.network, Some(Network::Regtest));
+
+        let payload = with_network(prove_request(None, Some(Network::Signet)), None).unwrap();
+        assert_eq!(payload.network, Some(Network::Signet));
+    }
+
+    #[test]
+    fn with_network_of_server() {
+        let payload = with_network(prove_request(None, None), Some(Network::Mainnet)).unwrap();
+        assert_eq!(payload.network, Some(Network::Mainnet));
+
+        let (status, _) = with_network(
+            prove_request(Some(Network::Testnet4), None),
+            Some(Network::Mainnet),
+        )
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = with_network(
+            prove_request(None, Some(Network::Regtest)),
+            Some(Network::Mainnet),
+        )
+        .unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
     }
 }
@@ -1,16 +1,25 @@
 use crate::{
     app, cli,
-    cli::{wallet, wallet::MIN_SATS, SpellCastParams, SpellCheckParams, SpellProveParams},
-    spell,
-    spell::{ProveRequest, ProveSpellTx, Spell},
+    cli::{
+        config::Config, psbt::psbt_base64, wallet, wallet::MIN_SATS, SpellCastParams,
+        SpellCheckParams, SpellProveParams, SpellRenderParams, SpellVars,
+    },
+    schema, spell,
+    spell::{resolve_network, ProveRequest, ProveSpellTx, Spell, CURRENT_VERSION},
     tx, SPELL_VK,
 };
-use anyhow::{ensure, Error, Result};
+use anyhow::{anyhow, ensure, Error, Result};
 use bitcoin::{
     consensus::encode::{deserialize_hex, serialize_hex},
-    Transaction,
+    OutPoint, Script, Transaction, Txid,
+};
+use charms_data::App;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
-use std::{future::Future, sync::Arc};
 
 pub trait Check {
     fn check(&self, params: SpellCheckParams) -> Result<()>;
@@ -27,6 +36,48 @@ pub trait Cast {
 pub struct SpellCli {
     pub app_prover: Arc<app::Prover>,
     pub spell_prover: Arc<spell::Prover>,
+    /// Registered apps (app alias registry) by their keys in spells.
+    pub keyed_apps: BTreeMap<String, App>,
+    /// Paths to the RISC-V binaries of registered apps.
+    pub app_bins: BTreeMap<App, PathBuf>,
+}
+
+impl SpellCli {
+    /// Keys in spells of the registered apps.
+    pub(crate) fn app_keys(&self) -> BTreeMap<App, String> {
+        self.keyed_apps
+            .iter()
+            .map(|(k, app)| (app.clone(), k.clone()))
+            .collect()
+    }
+
+    /// Read the spell, declaring registered apps it uses by their alias keys.
+    pub(crate) fn read_spell(&self, vars: &SpellVars, path: &Path) -> Result<Spell> {
+        self.read_spell_with(vars, path, &BTreeMap::new())
+    }
+
+    /// [`Self::read_spell`] with `extra` variables (e.g. set by earlier spells of a chain).
+    pub(crate) fn read_spell_with(
+        &self,
+        vars: &SpellVars,
+        path: &Path,
+        extra: &BTreeMap<String, String>,
+    ) -> Result<Spell> {
+        let mut spell = vars.read_spell_with(path, extra)?;
+        spell.add_apps(&self.keyed_apps);
+        Ok(spell)
+    }
+
+    /// `app_bins` with binaries of registered apps in the spell added.
+    pub(crate) fn app_bins(&self, spell: &Spell, app_bins: Vec<PathBuf>) -> Vec<PathBuf> {
+        let mut app_bins = app_bins;
+        for bin in spell.apps.values().filter_map(|app| self.app_bins.get(app)) {
+            if !app_bins.contains(bin) {
+                app_bins.push(bin.clone());
+            }
+        }
+        app_bins
+    }
 }
 
 impl Prove for SpellCli {
@@ -34,12 +85,16 @@ impl Prove for SpellCli {
         &self,
         SpellProveParams {
             spell,
+            vars,
             prev_txs,
             app_bins,
             funding_utxo,
             funding_utxo_value,
             change_address,
             fee_rate,
+            network,
+            refund,
+            psbt,
         }: SpellProveParams,
     ) -> Result<()> {
         // Parse funding UTXO early: to fail fast
@@ -47,13 +102,15 @@ impl Prove for SpellCli {
 
         ensure!(fee_rate >= 1.0, "fee rate must be >= 1.0");
 
-        let spell: Spell = serde_yaml::from_slice(&std::fs::read(spell)?)?;
+        let spell = self.read_spell(&vars, &spell)?;
 
-        let prev_txs = prev_txs
+        let prev_txs: Vec<Transaction> = prev_txs
             .into_iter()
             .map(|tx| Ok(deserialize_hex::<Transaction>(&tx)?))
             .collect::<Result<_>>()?;
+        let mut prev_txs_by_id = tx::txs_by_txid(prev_txs.clone());
 
+        let app_bins = self.app_bins(&spell, app_bins);
         let binaries = cli::app::binaries_by_vk(&self.app_prover, app_bins)?;
 
         let transactions = self
@@ -67,9 +124,25 @@ impl Prove for SpellCli {
                 change_address,
                 fee_rate,
                 charms_fee: None,
+                network,
+                refund: refund.refund(),
             })
             .await?;
 
+        if psbt {
+            let [commit_tx, spell_tx] = &transactions;
+            let commit_psbt = tx::to_psbt(commit_tx, &prev_txs_by_id)?;
+            prev_txs_by_id.insert(commit_tx.compute_txid(), commit_tx.clone());
+            let spell_psbt = tx::to_psbt(spell_tx, &prev_txs_by_id)?;
+
+            // Print JSON array of PSBTs
+            println!(
+                "{}",
+                serde_json::to_string(&[psbt_base64(&commit_psbt), psbt_base64(&spell_psbt)])?
+            );
+            return Ok(());
+        }
+
         // Convert transactions to hex and create JSON array
         let hex_txs: Vec<String> = transactions.iter().map(|tx| serialize_hex(tx)).collect();
 
@@ -81,10 +154,22 @@ impl Prove for SpellCli {
 }
 
 impl Check for SpellCli {
-    #[tracing::instrument(level = "debug", skip(self, spell, app_bins))]
-    fn check(&self, SpellCheckParams { spell, app_bins }: SpellCheckParams) -> Result<()> {
-        let mut spell: Spell = serde_yaml::from_slice(&std::fs::read(spell)?)?;
-        for u in spell.outs.iter_mut() {
+    #[tracing::instrument(level = "debug", skip(self, spell, vars, app_bins))]
+    fn check(
+        &self,
+        SpellCheckParams {
+            spell,
+            vars,
+            app_bins,
+            network,
+        }: SpellCheckParams,
+    ) -> Result<()> {
+        let mut spell = self.read_spell(&vars, &spell)?;
+        spell.network = Some(resolve_network(&[
+            ("--network", network),
+            ("spell", spell.network),
+        ])?);
+        for u in spell.outs.iter_mut().filter(|u| !u.is_op_return()) {
             u.sats.get_or_insert(crate::cli::wallet::MIN_SATS);
         }
 
@@ -94,22 +179,27 @@ impl Check for SpellCli {
             "all spell inputs must have utxo_id"
         );
 
-        let tx = tx::from_spell(&spell);
+        let tx = tx::from_spell(&spell)?;
 
-        let prev_txs = cli::tx::get_prev_txs(&tx)?;
+        let prev_txs = cli::tx::get_prev_txs(&tx, spell.network)?;
 
         let prev_spells = charms_client::prev_spells(&prev_txs, &SPELL_VK);
 
         let (norm_spell, app_private_inputs) = spell.normalized()?;
 
         ensure!(
-            charms_client::well_formed(&norm_spell, &prev_spells),
-            "spell is not well-formed"
+            norm_spell.version == CURRENT_VERSION,
+            "spell version {} is not the current version {}",
+            norm_spell.version,
+            CURRENT_VERSION
         );
+        charms_client::check_well_formed(&norm_spell, &prev_spells)
+            .map_err(|e| anyhow!("spell is not well-formed: {}", e))?;
 
+        let app_bins = self.app_bins(&spell, app_bins);
         let binaries = cli::app::binaries_by_vk(&self.app_prover, app_bins)?;
 
-        let charms_tx = spell.to_tx()?;
+        let charms_tx = charms_client::to_tx(&norm_spell, &prev_spells);
         self.app_prover.run_all(
             &binaries,
             &charms_tx,
@@ -127,76 +217,245 @@ impl Cast for SpellCli {
         &self,
         SpellCastParams {
             spell,
+            vars,
+            chain,
             app_bins,
             funding_utxo,
             fee_rate,
+            network,
+            refund,
         }: SpellCastParams,
     ) -> Result<()> {
         // Parse funding UTXO early: to fail fast
-        let funding_utxo = cli::tx::parse_outpoint(&funding_utxo)?;
+        let mut funding_utxo = cli::tx::parse_outpoint(&funding_utxo)?;
 
         ensure!(fee_rate >= 1.0, "fee rate must be >= 1.0");
-        let mut spell: Spell = serde_yaml::from_slice(&std::fs::read(spell)?)?;
-
-        spell_pre_checks(&spell)?;
-
-        for u in spell.outs.iter_mut() {
-            u.sats.get_or_insert(MIN_SATS);
+        let spells = match chain.is_empty() {
+            true => vec![spell],
+            false => chain,
+        };
+
+        // value of the previous spell's change output, funding the next spell
+        let mut change_value = None;
+
+        // transactions built so far: not known to the node yet
+        let mut chain_txs: BTreeMap<Txid, Transaction> = BTreeMap::new();
+        // `${chain.N.txid}` values of the spells built so far
+        let mut chain_vars = BTreeMap::new();
+        let mut signed_tx_hexes = vec![];
+
+        for (i, spell_path) in spells.iter().enumerate() {
+            let mut spell = self.read_spell_with(&vars, spell_path, &chain_vars)?;
+            let network = resolve_network(&[("--network", network), ("spell", spell.network)])?;
+            spell.network = Some(network);
+
+            spell_pre_checks(&spell)?;
+
+            for u in spell.outs.iter_mut().filter(|u| !u.is_op_return()) {
+                u.sats.get_or_insert(MIN_SATS);
+            }
+
+            let prev_txs = gather_prev_txs(&spell, &chain_txs)?;
+
+            let funding_utxo_value = match change_value {
+                Some(value) => value,
+                None => wallet::funding_utxo_value(&funding_utxo, Some(network))?,
+            };
+            let change_address = wallet::new_change_address(Some(network))?;
+            let change_script_pubkey = change_address.clone().assume_checked().script_pubkey();
+
+            let app_bins = self.app_bins(&spell, app_bins.clone());
+            let binaries = cli::app::binaries_by_vk(&self.app_prover, app_bins)?;
+
+            let [commit_tx, spell_tx] = self
+                .spell_prover
+                .prove_spell_tx(ProveRequest {
+                    spell,
+                    binaries,
+                    prev_txs,
+                    funding_utxo,
+                    funding_utxo_value,
+                    change_address,
+                    fee_rate,
+                    charms_fee: None,
+                    network: Some(network),
+                    refund: refund.refund(),
+                })
+                .await
+                .map_err(|e| anyhow!("spell {}: {}", spell_path.display(), e))?;
+
+            let signed_commit_tx_hex = wallet::sign_tx(&commit_tx, &chain_txs, Some(network))?;
+            chain_txs.insert(commit_tx.compute_txid(), commit_tx);
+            let signed_spell_tx_hex = wallet::sign_tx(&spell_tx, &chain_txs, Some(network))?;
+            let spell_tx: Transaction = deserialize_hex(&signed_spell_tx_hex)?;
+
+            if i + 1 < spells.len() {
+                // fund the next spell with the change output of this one
+                let (change_utxo, value) = change_output(&spell_tx, &change_script_pubkey)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "spell {}: no change output to fund the next spell",
+                            spell_path.display()
+                        )
+                    })?;
+                funding_utxo = change_utxo;
+                change_value = Some(value);
+            }
+            chain_vars.insert(chain_txid_var(i), spell_tx.compute_txid().to_string());
+            chain_txs.insert(spell_tx.compute_txid(), spell_tx);
+
+            signed_tx_hexes.extend([signed_commit_tx_hex, signed_spell_tx_hex]);
         }
 
-        let prev_txs = gather_prev_txs(&spell)?;
-
-        let funding_utxo_value = wallet::funding_utxo_value(&funding_utxo)?;
-        let change_address = wallet::new_change_address()?;
+        // Print JSON array of transaction hexes
+        println!("{}", serde_json::to_string(&signed_tx_hexes)?);
 
-        let binaries = cli::app::binaries_by_vk(&self.app_prover, app_bins)?;
+        Ok(())
+    }
+}
 
-        let [commit_tx, spell_tx] = self
-            .spell_prover
-            .prove_spell_tx(ProveRequest {
-                spell,
-                binaries,
-                prev_txs,
-                funding_utxo,
-                funding_utxo_value,
-                change_address,
-                fee_rate,
-                charms_fee: None,
-            })
-            .await?;
+/// Print the spell with the variables substituted.
+pub fn render(
+    SpellRenderParams { spell, vars, json }: SpellRenderParams,
+    config: &Config,
+) -> Result<()> {
+    let mut spell = vars.read_spell(&spell)?;
+    spell.add_apps(&config.keyed_apps());
+    cli::print_output(&spell, json)
+}
 
-        let signed_commit_tx_hex = wallet::sign_tx(&serialize_hex(&commit_tx))?;
-        let signed_spell_tx_hex = wallet::sign_spell_tx(&serialize_hex(&spell_tx), &commit_tx)?;
+/// Print the JSON Schema of the spell source format.
+pub fn schema() -> Result<()> {
+    cli::print_output(&schema::spell_schema(), true)
+}
 
-        // Print JSON array of transaction hexes
-        println!(
-            "{}",
-            serde_json::to_string(&[signed_commit_tx_hex, signed_spell_tx_hex])?
-        );
+/// Variable set to the transaction ID of the `i`-th spell of a chain.
+fn chain_txid_var(i: usize) -> String {
+    format!("chain.{}.txid", i)
+}
 
-        Ok(())
-    }
+/// The change output of `spell_tx` (the last one paying to `change_script_pubkey`) and its value
+/// in sats.
+fn change_output(spell_tx: &Transaction, change_script_pubkey: &Script) -> Option<(OutPoint, u64)> {
+    let vout = spell_tx
+        .output
+        .iter()
+        .rposition(|tx_out| tx_out.script_pubkey.as_script() == change_script_pubkey)?;
+    Some((
+        OutPoint::new(spell_tx.compute_txid(), vout as u32),
+        spell_tx.output[vout].value.to_sat(),
+    ))
 }
 
-#[tracing::instrument(level = "debug", skip(spell))]
-fn gather_prev_txs(spell: &Spell) -> Result<Vec<Transaction>, Error> {
-    let tx = tx::from_spell(&spell);
-    let prev_txs = cli::tx::get_prev_txs(&tx)?;
+/// Get the transactions creating the spell's inputs: from `chain_txs` (built, but not yet
+/// broadcast) if there, otherwise from the node.
+#[tracing::instrument(level = "debug", skip(spell, chain_txs))]
+fn gather_prev_txs(
+    spell: &Spell,
+    chain_txs: &BTreeMap<Txid, Transaction>,
+) -> Result<Vec<Transaction>, Error> {
+    let mut tx = tx::from_spell(spell)?;
+    let in_chain: BTreeSet<Txid> = tx
+        .input
+        .iter()
+        .map(|input| input.previous_output.txid)
+        .filter(|txid| chain_txs.contains_key(txid))
+        .collect();
+    tx.input
+        .retain(|input| !in_chain.contains(&input.previous_output.txid));
+
+    let mut prev_txs = match tx.input.is_empty() {
+        true => vec![],
+        false => cli::tx::get_prev_txs(&tx, spell.network)?,
+    };
+    prev_txs.extend(in_chain.iter().map(|txid| chain_txs[txid].clone()));
     Ok(prev_txs)
 }
 
 #[tracing::instrument(level = "debug", skip(spell))]
-fn spell_pre_checks(spell: &Spell) -> Result<(), Error> {
+pub(crate) fn spell_pre_checks(spell: &Spell) -> Result<(), Error> {
     // make sure spell inputs all have utxo_id
     ensure!(
         spell.ins.iter().all(|u| u.utxo_id.is_some()),
         "all spell inputs must have utxo_id"
     );
 
-    // make sure spell outputs all have addresses
-    ensure!(
-        spell.outs.iter().all(|u| u.address.is_some()),
-        "all spell outputs must have addresses"
-    );
+    // make sure spell outputs all have valid destinations
+    for (i, u) in spell.outs.iter().enumerate() {
+        tx::output_script_pubkey(u, spell.network())
+            .map_err(|e| anyhow!("spell output {}: {}", i, e))?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{absolute::LockTime, transaction::Version, Amount, ScriptBuf, TxOut};
+
+    fn chain_tx(outputs: &[(u64, &ScriptBuf)]) -> Transaction {
+        Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: outputs
+                .iter()
+                .map(|&(sats, script_pubkey)| TxOut {
+                    value: Amount::from_sat(sats),
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn chain_spell_spends_earlier_spell() {
+        let script_pubkey =
+            ScriptBuf::from_hex("0014751e76e8199196d454941c45d1b3a323f1433bd6").unwrap();
+        let tx_0 = chain_tx(&[(1000, &script_pubkey)]);
+        let chain_txs: BTreeMap<Txid, Transaction> = [(tx_0.compute_txid(), tx_0.clone())].into();
+        let chain_vars = [(chain_txid_var(0), tx_0.compute_txid().to_string())].into();
+
+        let source = r#"
+version: 2
+apps: {}
+ins:
+  - utxo_id: ${chain.0.txid}:0
+outs:
+  - script_pubkey: 0014751e76e8199196d454941c45d1b3a323f1433bd6
+"#;
+        let vars = SpellVars {
+            var: vec![],
+            vars: None,
+        };
+        let spell: Spell =
+            serde_yaml::from_str(&vars.render_with(source, &chain_vars).unwrap()).unwrap();
+        assert_eq!(
+            tx::from_spell(&spell).unwrap().input[0].previous_output,
+            OutPoint::new(tx_0.compute_txid(), 0)
+        );
+
+        // the earlier spell's transaction is not on the node yet: it is taken from the chain
+        let prev_txs = gather_prev_txs(&spell, &chain_txs).unwrap();
+        assert_eq!(prev_txs, vec![tx_0]);
+
+        // placeholders of later spells are not set yet
+        assert!(vars.render_with("${chain.1.txid}:0", &chain_vars).is_err());
+    }
+
+    #[test]
+    fn change_output_funds_next_spell() {
+        let change = ScriptBuf::from_hex("0014751e76e8199196d454941c45d1b3a323f1433bd6").unwrap();
+        let other = ScriptBuf::new_op_return([1u8]);
+        let spell_tx = chain_tx(&[(1000, &change), (0, &other), (5000, &change), (0, &other)]);
+
+        // the change output is the last one to the change address
+        assert_eq!(
+            change_output(&spell_tx, &change),
+            Some((OutPoint::new(spell_tx.compute_txid(), 2), 5000))
+        );
+
+        let spell_tx = chain_tx(&[(0, &other)]);
+        assert_eq!(change_output(&spell_tx, &change), None);
+    }
+}
@@ -0,0 +1,150 @@
+use crate::{
+    cli,
+    cli::{
+        spell::{spell_pre_checks, SpellCli},
+        wallet,
+        wallet::MIN_SATS,
+        SwapOfferParams, SwapTakeParams,
+    },
+    spell::{resolve_network, Output, ProveRequest, ProveSpellTx},
+    swap, tx,
+};
+use anyhow::{anyhow, ensure, Result};
+use bitcoin::{consensus::encode::deserialize_hex, Amount, Transaction};
+use std::{collections::BTreeMap, future::Future};
+
+pub trait Swap {
+    fn offer(&self, params: SwapOfferParams) -> Result<()>;
+    fn take(&self, params: SwapTakeParams) -> impl Future<Output = Result<()>>;
+}
+
+impl Swap for SpellCli {
+    fn offer(
+        &self,
+        SwapOfferParams {
+            utxo,
+            price,
+            address,
+            network,
+        }: SwapOfferParams,
+    ) -> Result<()> {
+        let utxo = cli::tx::parse_outpoint(&utxo)?;
+        let network = resolve_network(&[("--network", network)])?;
+
+        let payment_address = match address {
+            Some(address) => address,
+            None => wallet::new_address(Some(network))?,
+        };
+        let payment_script_pubkey = network.check_address(&payment_address)?.script_pubkey();
+
+        let offer_tx = swap::offer_tx(utxo, Amount::from_sat(price), payment_script_pubkey);
+
+        // make sure there is something to offer
+        let prev_txs = cli::tx::get_prev_txs(&offer_tx, Some(network))?;
+        let offered_utxo_spell = tx::spell(&prev_txs[0], &self.app_keys())
+            .ok_or_else(|| anyhow!("offered UTXO {} has no charms", utxo))?;
+        ensure!(
+            offered_utxo_spell
+                .outs
+                .get(utxo.vout as usize)
+                .and_then(|u| u.charms.as_ref())
+                .is_some_and(|charms| !charms.is_empty()),
+            "offered UTXO {} has no charms",
+            utxo
+        );
+
+        let signed_offer_tx_hex = wallet::sign_offer_tx(&offer_tx, Some(network))?;
+        swap::check_offer(&deserialize_hex(&signed_offer_tx_hex)?)?;
+
+        println!("{}", signed_offer_tx_hex);
+
+        Ok(())
+    }
+
+    async fn take(
+        &self,
+        SwapTakeParams {
+            offer,
+            address,
+            app_bins,
+            funding_utxo,
+            fee_rate,
+            network,
+        }: SwapTakeParams,
+    ) -> Result<()> {
+        // Parse funding UTXO early: to fail fast
+        let funding_utxo = cli::tx::parse_outpoint(&funding_utxo)?;
+
+        ensure!(fee_rate >= 1.0, "fee rate must be >= 1.0");
+        let offer_tx: Transaction = deserialize_hex(&offer)?;
+        swap::check_offer(&offer_tx)?;
+        let network = resolve_network(&[("--network", network)])?;
+
+        // the spell only spends the offered UTXO
+        let prev_txs = cli::tx::get_prev_txs(&offer_tx, Some(network))?;
+        let offered_utxo = offer_tx.input[0].previous_output;
+        let offered_utxo_spell = tx::spell(&prev_txs[0], &self.app_keys())
+            .ok_or_else(|| anyhow!("offered UTXO {} has no charms", offered_utxo))?;
+
+        let charms_address = match address {
+            Some(address) => address,
+            None => wallet::new_address(Some(network))?,
+        };
+        let charms_destination = Output {
+            address: Some(charms_address),
+            ..Default::default()
+        };
+
+        let mut spell = swap::take_spell(&offer_tx, &offered_utxo_spell, charms_destination)?;
+        spell.network = Some(network);
+
+        spell_pre_checks(&spell)?;
+
+        for u in spell.outs.iter_mut().filter(|u| !u.is_op_return()) {
+            u.sats.get_or_insert(MIN_SATS);
+        }
+
+        let funding_utxo_value = wallet::funding_utxo_value(&funding_utxo, Some(network))?;
+        let offered_utxo_value = prev_txs[0].output[offered_utxo.vout as usize].value;
+        let spell_outs_value: u64 = spell.outs.iter().filter_map(|u| u.sats).sum();
+        ensure!(
+            funding_utxo_value + offered_utxo_value.to_sat() > spell_outs_value,
+            "funding UTXO value is not enough to pay for the offer"
+        );
+
+        let change_address = wallet::new_change_address(Some(network))?;
+
+        let app_bins = self.app_bins(&spell, app_bins);
+        let binaries = cli::app::binaries_by_vk(&self.app_prover, app_bins)?;
+
+        let [commit_tx, mut spell_tx] = self
+            .spell_prover
+            .prove_spell_tx(ProveRequest {
+                spell,
+                binaries,
+                prev_txs,
+                funding_utxo,
+                funding_utxo_value,
+                change_address,
+                fee_rate,
+                charms_fee: None,
+                network: Some(network),
+                refund: None,
+            })
+            .await?;
+
+        swap::complete_swap_tx(&mut spell_tx, &offer_tx)?;
+
+        let signed_commit_tx_hex = wallet::sign_tx(&commit_tx, &BTreeMap::new(), Some(network))?;
+        let commit_txs = [(commit_tx.compute_txid(), commit_tx)].into();
+        let signed_spell_tx_hex = wallet::sign_tx(&spell_tx, &commit_txs, Some(network))?;
+
+        // Print JSON array of transaction hexes
+        println!(
+            "{}",
+            serde_json::to_string(&[signed_commit_tx_hex, signed_spell_tx_hex])?
+        );
+
+        Ok(())
+    }
+}
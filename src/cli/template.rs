@@ -0,0 +1,168 @@
+use crate::{cli::SpellVars, spell::Spell};
+use anyhow::{anyhow, bail, ensure, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+impl SpellVars {
+    /// Read the spell from `path`, substituting the variables.
+    pub(crate) fn read_spell(&self, path: &Path) -> Result<Spell> {
+        self.read_spell_with(path, &BTreeMap::new())
+    }
+
+    /// Read the spell from `path`, substituting the variables and the `extra` ones (which take
+    /// precedence).
+    pub(crate) fn read_spell_with(
+        &self,
+        path: &Path,
+        extra: &BTreeMap<String, String>,
+    ) -> Result<Spell> {
+        Ok(serde_yaml::from_str(
+            &self.render_with(&read_source_file(path)?, extra)?,
+        )?)
+    }
+
+    /// Read the spell source from `path`, substituting the variables.
+    pub(crate) fn read_source(&self, path: &Path) -> Result<String> {
+        self.render_with(&read_source_file(path)?, &BTreeMap::new())
+    }
+
+    /// Substitute the variables and the `extra` ones (which take precedence) in `source`.
+    pub(crate) fn render_with(
+        &self,
+        source: &str,
+        extra: &BTreeMap<String, String>,
+    ) -> Result<String> {
+        let mut values = self.values()?;
+        values.extend(extra.iter().map(|(k, v)| (k.clone(), v.clone())));
+        render(source, &values)
+    }
+
+    /// Values of the variables: from `--vars` file, overridden by `--var`.
+    fn values(&self) -> Result<BTreeMap<String, String>> {
+        let mut values = match &self.vars {
+            Some(path) => read_vars(path)?,
+            None => BTreeMap::new(),
+        };
+        values.extend(self.var.iter().cloned());
+        Ok(values)
+    }
+}
+
+fn read_source_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).map_err(|e| anyhow!("error reading {:?}: {}", path, e))
+}
+
+/// Parse a `key=value` variable.
+pub(crate) fn parse_var(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected `key=value`, got `{}`", s))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Read variables from a YAML/JSON file: a map of variable names to values (strings, numbers or
+/// booleans).
+fn read_vars(path: &Path) -> Result<BTreeMap<String, String>> {
+    let vars: BTreeMap<String, serde_yaml::Value> = serde_yaml::from_slice(
+        &std::fs::read(path).map_err(|e| anyhow!("error reading {:?}: {}", path, e))?,
+    )?;
+    vars.into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                serde_yaml::Value::String(s) => s,
+                serde_yaml::Value::Number(n) => n.to_string(),
+                serde_yaml::Value::Bool(b) => b.to_string(),
+                _ => bail!("variable {} must be a string, number or boolean", key),
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+/// Substitute `${key}` placeholders in `source` with the values of `vars`.
+/// `$${` is an escaped `${`. Fails if any of the variables is not set.
+pub(crate) fn render(source: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    let mut rendered = String::with_capacity(source.len());
+    let mut unresolved = BTreeSet::new();
+
+    let mut rest = source;
+    while let Some(i) = rest.find("${") {
+        if rest[..i].ends_with('$') {
+            rendered.push_str(&rest[..i - 1]);
+            rendered.push_str("${");
+            rest = &rest[i + 2..];
+            continue;
+        }
+        rendered.push_str(&rest[..i]);
+        let Some(len) = rest[i + 2..].find('}') else {
+            bail!("unterminated placeholder: {}", &rest[i..]);
+        };
+        let key = &rest[i + 2..i + 2 + len];
+        match vars.get(key) {
+            Some(value) => rendered.push_str(value),
+            None => {
+                unresolved.insert(key);
+            }
+        }
+        rest = &rest[i + 2 + len + 1..];
+    }
+    rendered.push_str(rest);
+
+    ensure!(
+        unresolved.is_empty(),
+        "unresolved variables: {}",
+        unresolved.into_iter().collect::<Vec<_>>().join(", ")
+    );
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_placeholders() {
+        let vars: BTreeMap<String, String> = [
+            (
+                "utxo".to_string(),
+                "f72700ac56bd4dd61f2ccb4acdf21d0b11bb294fc3efa9012b77903932197d2f:2".to_string(),
+            ),
+            ("amount".to_string(), "100".to_string()),
+        ]
+        .into();
+
+        let source = r#"
+ins:
+  - utxo_id: ${utxo}
+    charms:
+      $00: ${amount}
+outs:
+  - charms:
+      $00: ${amount}
+    op_return: $${not_a_var}
+"#;
+        let rendered = render(source, &vars).unwrap();
+        assert!(rendered.contains(
+            "utxo_id: f72700ac56bd4dd61f2ccb4acdf21d0b11bb294fc3efa9012b77903932197d2f:2\n"
+        ));
+        assert!(rendered.contains("$00: 100\n"));
+        assert!(rendered.contains("op_return: ${not_a_var}\n"));
+        assert!(!rendered.contains("${amount}"));
+
+        let err = render("${a} ${b} ${utxo} ${a}", &vars).unwrap_err();
+        assert_eq!(err.to_string(), "unresolved variables: a, b");
+
+        assert!(render("${utxo", &vars).is_err());
+    }
+
+    #[test]
+    fn parse_vars() {
+        assert_eq!(
+            parse_var("fee=1=2").unwrap(),
+            ("fee".to_string(), "1=2".to_string())
+        );
+        assert!(parse_var("fee").is_err());
+    }
+}
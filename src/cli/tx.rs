@@ -1,10 +1,18 @@
-use crate::{cli, tx};
-use anyhow::{anyhow, Result};
+use crate::{
+    cli,
+    cli::{config::Config, psbt, wallet, TxRefundParams, TxSource, TxTraceParams},
+    script::Refund,
+    spell::{resolve_network, Network, Spell},
+    tx, SPELL_VK,
+};
+use anyhow::{anyhow, ensure, Result};
 use bitcoin::{
-    consensus::encode::{deserialize_hex, serialize_hex},
-    OutPoint, Transaction,
+    consensus::encode::deserialize_hex, hashes::Hash, FeeRate, OutPoint, Transaction, Txid,
 };
-use std::process::Command;
+use bitcoincore_rpc::{Auth, Client, RpcApi};
+use charms_client::{tx::extract_and_verify_spell, SpellError, SpellVersions};
+use charms_data::UtxoId;
+use std::collections::BTreeSet;
 
 pub(crate) fn parse_outpoint(s: &str) -> Result<OutPoint> {
     let parts: Vec<&str> = s.split(':').collect();
@@ -15,25 +23,91 @@ pub(crate) fn parse_outpoint(s: &str) -> Result<OutPoint> {
     Ok(OutPoint::new(parts[0].parse()?, parts[1].parse()?))
 }
 
-pub fn tx_show_spell(tx: String, json: bool) -> Result<()> {
+pub fn tx_show_spell(tx: String, json: bool, config: &Config) -> Result<()> {
     let tx = deserialize_hex::<Transaction>(&tx)?;
 
-    match tx::spell(&tx) {
-        Some(spell) => cli::print_output(&spell, json)?,
-        None => eprintln!("No spell found in the transaction"),
+    match extract_and_verify_spell(&tx, SPELL_VK) {
+        Ok(norm_spell) => {
+            cli::print_output(&Spell::denormalized(&norm_spell, &config.app_keys()), json)?
+        }
+        Err(SpellError::NoSpell) => eprintln!("No spell found in the transaction"),
+        Err(e) => eprintln!("Incorrect spell in the transaction: {}", e),
     }
 
     Ok(())
 }
 
-pub(crate) fn get_prev_txs(tx: &Transaction) -> Result<Vec<Transaction>> {
-    let cmd_output = Command::new("bash")
-        .args(&[
-            "-c", format!("bitcoin-cli decoderawtransaction {} | jq -r '.vin[].txid' | sort | uniq | xargs -I {{}} bitcoin-cli getrawtransaction {{}} | paste -sd, -", serialize_hex(tx)).as_str()
-        ])
-        .output()?;
-    String::from_utf8(cmd_output.stdout)?
-        .split(',')
-        .map(|s| Ok(deserialize_hex::<Transaction>(s.trim())?))
+pub fn tx_trace(params: TxTraceParams, config: &Config) -> Result<()> {
+    let utxo = UtxoId::from_str(&params.utxo)?;
+    let versions = SpellVersions::cached(SPELL_VK);
+
+    let lineage = match params.source {
+        TxSource::BitcoinCli => charms_client::lineage(&utxo, params.depth, &versions, |txid| {
+            wallet::get_tx(&txid.to_string(), params.network)
+        })?,
+        TxSource::Rpc => {
+            let rpc = Client::new(
+                &config.rpc.url(),
+                Auth::UserPass(config.rpc.user(), config.rpc.password()),
+            )?;
+            charms_client::lineage(&utxo, params.depth, &versions, |txid| {
+                Ok::<_, anyhow::Error>(
+                    rpc.get_raw_transaction(&Txid::from_byte_array(txid.0), None)?,
+                )
+            })?
+        }
+    };
+
+    cli::print_output(&lineage, params.json)
+}
+
+pub fn tx_refund(
+    TxRefundParams {
+        commit_tx,
+        spell_tx,
+        refund_pubkey,
+        refund_timeout,
+        address,
+        fee_rate,
+        network,
+    }: TxRefundParams,
+) -> Result<()> {
+    ensure!(fee_rate >= 1.0, "fee rate must be >= 1.0");
+    let commit_tx: Transaction = deserialize_hex(&commit_tx)?;
+    let spell_tx: Transaction = deserialize_hex(&spell_tx)?;
+    let network = resolve_network(&[("--network", network)])?;
+
+    let address = match address {
+        Some(address) => address,
+        None => wallet::new_address(Some(network))?,
+    };
+    let destination_script_pubkey = network.check_address(&address)?.script_pubkey();
+
+    let refund = Refund {
+        pubkey: refund_pubkey,
+        timeout: refund_timeout,
+    };
+    let fee_rate = FeeRate::from_sat_per_kwu((fee_rate * 250.0) as u64);
+    let psbt = tx::refund_psbt(
+        &commit_tx,
+        &spell_tx,
+        &refund,
+        destination_script_pubkey,
+        fee_rate,
+    )?;
+    println!("{}", psbt::psbt_base64(&psbt));
+    Ok(())
+}
+
+/// Get the transactions creating the inputs of `tx` from the node of the network.
+pub(crate) fn get_prev_txs(tx: &Transaction, network: Option<Network>) -> Result<Vec<Transaction>> {
+    let txids: BTreeSet<Txid> = tx
+        .input
+        .iter()
+        .map(|input| input.previous_output.txid)
+        .collect();
+    txids
+        .iter()
+        .map(|txid| wallet::get_tx(&txid.to_string(), network))
         .collect()
 }
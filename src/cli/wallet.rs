@@ -1,17 +1,20 @@
 use crate::{
     cli,
     cli::WalletListParams,
-    spell::{KeyedCharms, Spell},
-    tx,
+    spell::{KeyedCharms, Network, Spell},
     utils::str_index,
+    SPELL_VK,
+};
+use anyhow::{anyhow, ensure, Result};
+use bitcoin::{
+    address::NetworkUnchecked, consensus::encode::serialize_hex, hashes::Hash, Address, OutPoint,
+    Transaction, Txid,
 };
-use anyhow::{ensure, Result};
-use bitcoin::{address::NetworkUnchecked, hashes::Hash, Address, OutPoint, Transaction};
 use charms_data::{App, Data, TxId, UtxoId};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{BTreeMap, BTreeSet},
-    process::{Command, Stdio},
+    process::Command,
 };
 
 pub trait List {
@@ -19,9 +22,41 @@ pub trait List {
 }
 
 pub struct WalletCli {
-    // pub app_prover: Rc<app::Prover>,
-    // pub sp1_client: Rc<Box<dyn Prover<CpuProverComponents>>>,
-    // pub spell_prover: Rc<spell::Prover>,
+    /// Bitcoin network of the wallet. If set, passed to `bitcoin-cli` as `-chain`.
+    pub network: Option<Network>,
+    /// Keys of registered apps (app alias registry), used instead of numbered keys.
+    pub app_keys: BTreeMap<App, String>,
+}
+
+/// `bitcoin-cli -chain` value for the network.
+fn bitcoin_cli_chain(network: Network) -> &'static str {
+    match network {
+        Network::Mainnet => "main",
+        Network::Testnet4 => "testnet4",
+        Network::Signet => "signet",
+        Network::Regtest => "regtest",
+    }
+}
+
+/// `bitcoin-cli` command talking to the node of the network (`-chain`), if set.
+fn bitcoin_cli_command(network: Option<Network>) -> Command {
+    let mut cmd = Command::new("bitcoin-cli");
+    if let Some(network) = network {
+        cmd.arg(format!("-chain={}", bitcoin_cli_chain(network)));
+    }
+    cmd
+}
+
+/// Run `bitcoin-cli` with `args` for the network. Return its output (stdout).
+pub(crate) fn bitcoin_cli(network: Option<Network>, args: &[&str]) -> Result<Vec<u8>> {
+    let output = bitcoin_cli_command(network).args(args).output()?;
+    ensure!(
+        output.status.success(),
+        "bitcoin-cli {} failed: {}",
+        args[0],
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(output.stdout)
 }
 
 #[derive(Debug, Deserialize)]
@@ -50,55 +85,71 @@ struct AppsAndCharmsOutputs {
 
 impl List for WalletCli {
     fn list(&self, params: WalletListParams) -> Result<()> {
-        let b_cli = Command::new("bitcoin-cli")
-            .args(&["listunspent", "0"]) // include outputs with 0 confirmations
-            .stdout(Stdio::piped())
-            .spawn()?;
-        let output = b_cli.wait_with_output()?;
-        let b_list_unspent: Vec<BListUnspentItem> = serde_json::from_slice(&output.stdout)?;
+        // include outputs with 0 confirmations
+        let output = bitcoin_cli(self.network, &["listunspent", "0"])?;
+        let b_list_unspent: Vec<BListUnspentItem> = serde_json::from_slice(&output)?;
 
-        let unspent_charms_outputs = outputs_with_charms(b_list_unspent)?;
+        let unspent_charms_outputs = self.outputs_with_charms(b_list_unspent)?;
 
         cli::print_output(&unspent_charms_outputs, params.json)?;
         Ok(())
     }
 }
 
-fn outputs_with_charms(b_list_unspent: Vec<BListUnspentItem>) -> Result<AppsAndCharmsOutputs> {
-    let txid_set = b_list_unspent
-        .iter()
-        .map(|item| item.txid.clone())
-        .collect::<BTreeSet<_>>();
-    let spells = txs_with_spells(txid_set.into_iter())?;
-    let utxos_with_charms: BTreeMap<UtxoId, (BListUnspentItem, ParsedCharms)> =
-        utxos_with_charms(spells, b_list_unspent);
-    let apps = collect_apps(&utxos_with_charms);
-
-    Ok(AppsAndCharmsOutputs {
-        apps: enumerate_apps(&apps),
-        outputs: pretty_outputs(utxos_with_charms, &apps),
-    })
-}
-
-fn txs_with_spells(txid_iter: impl Iterator<Item = String>) -> Result<BTreeMap<TxId, Spell>> {
-    let txs_with_spells = txid_iter
-        .map(|txid| {
-            let tx: Transaction = get_tx(&txid)?;
-            Ok(tx)
-        })
-        .map(|tx_result: Result<Transaction>| {
-            let tx = tx_result?;
-            let spell_opt = tx::spell(&tx);
-            Ok(spell_opt.map(|spell| (TxId(tx.compute_txid().to_byte_array()), spell)))
-        })
-        .filter_map(|tx_result| match tx_result {
-            Ok(Some(v)) => Some(Ok(v)),
-            Ok(None) => None,
-            Err(e) => Some(Err(e)),
+impl WalletCli {
+    fn outputs_with_charms(
+        &self,
+        b_list_unspent: Vec<BListUnspentItem>,
+    ) -> Result<AppsAndCharmsOutputs> {
+        let txid_set = b_list_unspent
+            .iter()
+            .map(|item| item.txid.clone())
+            .collect::<BTreeSet<_>>();
+        let spells = self.txs_with_spells(txid_set.into_iter())?;
+        let utxos_with_charms: BTreeMap<UtxoId, (BListUnspentItem, ParsedCharms)> =
+            utxos_with_charms(spells, b_list_unspent);
+        let apps = collect_apps(&utxos_with_charms, &self.app_keys);
+
+        Ok(AppsAndCharmsOutputs {
+            apps: enumerate_apps(&apps),
+            outputs: pretty_outputs(utxos_with_charms, &apps),
         })
-        .collect::<Result<_>>()?;
+    }
 
-    Ok(txs_with_spells)
+    fn txs_with_spells(
+        &self,
+        txid_iter: impl Iterator<Item = String>,
+    ) -> Result<BTreeMap<TxId, Spell>> {
+        let txs = txid_iter
+            .map(|txid| get_tx(&txid, self.network))
+            .collect::<Result<Vec<Transaction>>>()?;
+        let spells = charms_client::tx::verify_spells(&txs, SPELL_VK);
+
+        let txs_with_spells = txs
+            .iter()
+            .zip(spells)
+            .filter_map(|(tx, spell_result)| match spell_result {
+                Ok(norm_spell) => Some((
+                    TxId(tx.compute_txid().to_byte_array()),
+                    Spell::denormalized(&norm_spell, &self.app_keys),
+                )),
+                Err(e) => {
+                    tracing::debug!("spell verification failed: {:?}", e);
+                    None
+                }
+            })
+            .collect();
+
+        Ok(txs_with_spells)
+    }
+}
+
+/// Get the transaction from the node of the network.
+pub(crate) fn get_tx(txid: &str, network: Option<Network>) -> Result<Transaction> {
+    let output = bitcoin_cli(network, &["getrawtransaction", txid])?;
+    let tx_hex = String::from_utf8(output)?;
+    let tx = bitcoin::consensus::encode::deserialize_hex(tx_hex.trim())?;
+    Ok(tx)
 }
 
 fn utxos_with_charms(
@@ -132,15 +183,24 @@ fn parsed_charms(keyed_charms: &KeyedCharms, apps: &BTreeMap<String, App>) -> Pa
 
 fn collect_apps(
     strings_of_charms: &BTreeMap<UtxoId, (BListUnspentItem, ParsedCharms)>,
+    app_keys: &BTreeMap<App, String>,
 ) -> BTreeMap<App, String> {
     let apps: BTreeSet<App> = strings_of_charms
         .iter()
         .flat_map(|(_utxo, (_sats, charms))| charms.keys())
         .cloned()
         .collect();
+    let mut indexes = (0..)
+        .map(|i| str_index(&i))
+        .filter(|k| !app_keys.values().any(|key| key == k));
     apps.into_iter()
-        .zip(0..)
-        .map(|(app, i)| (app, str_index(&i)))
+        .map(|app| {
+            let key = match app_keys.get(&app) {
+                Some(key) => key.clone(),
+                None => indexes.next().expect("indexes are unlimited"),
+            };
+            (app, key)
+        })
         .collect()
 }
 
@@ -175,67 +235,103 @@ fn pretty_outputs(
         .collect()
 }
 
-fn get_tx(txid: &str) -> Result<Transaction> {
-    let b_cli = Command::new("bitcoin-cli")
-        .args(&["getrawtransaction", txid])
-        .stdout(Stdio::piped())
-        .spawn()?;
-    let output = b_cli.wait_with_output()?;
-    ensure!(
-        output.status.success(),
-        "bitcoin-cli getrawtransaction failed"
-    );
-    let tx_hex = String::from_utf8(output.stdout)?;
-    let tx_hex = tx_hex.trim();
-    let tx = bitcoin::consensus::encode::deserialize_hex(&(tx_hex))?;
-    Ok(tx)
+pub const MIN_SATS: u64 = 1000;
+
+#[derive(Debug, Deserialize)]
+struct BSignedTx {
+    hex: String,
 }
 
-pub const MIN_SATS: u64 = 1000;
+#[derive(Debug, Deserialize)]
+struct BTxOut {
+    value: f64,
+}
 
-pub(crate) fn sign_spell_tx(spell_tx_hex: &String, commit_tx: &Transaction) -> Result<String> {
-    let cmd_line = format!(
-        r#"bitcoin-cli signrawtransactionwithwallet {} '[{{"txid":"{}","vout":0,"scriptPubKey":"{}","amount":{}}}]' | jq -r '.hex'"#,
-        spell_tx_hex,
-        commit_tx.compute_txid(),
-        &commit_tx.output[0].script_pubkey.to_hex_string(),
-        commit_tx.output[0].value.to_btc()
-    );
-    let cmd_out = Command::new("bash")
-        .args(&["-c", cmd_line.as_str()])
-        .output()?;
-    Ok(String::from_utf8(cmd_out.stdout)?.trim().to_string())
-}
-
-pub(crate) fn sign_tx(tx_hex: &str) -> Result<String> {
-    let cmd_out = Command::new("bash")
-        .args(&[
-            "-c",
-            format!(
-                "bitcoin-cli signrawtransactionwithwallet {} | jq -r '.hex'",
-                tx_hex
-            )
-            .as_str(),
-        ])
-        .output()?;
-    Ok(String::from_utf8(cmd_out.stdout)?.trim().to_string())
-}
-
-pub(crate) fn new_change_address() -> Result<Address<NetworkUnchecked>> {
-    let cmd_out = Command::new("bitcoin-cli")
-        .args(&["getrawchangeaddress"])
-        .output()?;
-    Ok(String::from_utf8(cmd_out.stdout)?
-        .trim()
-        .to_string()
-        .parse()?)
-}
-
-pub(crate) fn funding_utxo_value(utxo: &OutPoint) -> Result<u64> {
-    let cmd = format!(
-        "bitcoin-cli gettxout {} {} | jq -r '.value*100000000 | round'",
-        utxo.txid, utxo.vout
-    );
-    let cmd_out = Command::new("bash").args(&["-c", &cmd]).output()?;
-    Ok(String::from_utf8(cmd_out.stdout)?.trim().parse()?)
+/// Sign `tx` with the wallet. `parent_txs` are transactions not (yet) known to the node, whose
+/// outputs `tx` may spend: their outputs are passed to `bitcoin-cli` explicitly.
+pub(crate) fn sign_tx(
+    tx: &Transaction,
+    parent_txs: &BTreeMap<Txid, Transaction>,
+    network: Option<Network>,
+) -> Result<String> {
+    let prev_outs = tx
+        .input
+        .iter()
+        .filter_map(|input| {
+            let OutPoint { txid, vout } = input.previous_output;
+            let tx_out = parent_txs.get(&txid)?.output.get(vout as usize)?;
+            Some(serde_json::json!({
+                "txid": txid.to_string(),
+                "vout": vout,
+                "scriptPubKey": tx_out.script_pubkey.to_hex_string(),
+                "amount": tx_out.value.to_btc(),
+            }))
+        })
+        .collect::<Vec<_>>();
+    let output = bitcoin_cli(
+        network,
+        &[
+            "signrawtransactionwithwallet",
+            &serialize_hex(tx),
+            &serde_json::to_string(&prev_outs)?,
+        ],
+    )?;
+    let signed: BSignedTx = serde_json::from_slice(&output)?;
+    Ok(signed.hex)
+}
+
+/// Sign the offer transaction's only input `SIGHASH_SINGLE|ANYONECANPAY` with the wallet: the
+/// signature stays valid when more inputs and outputs are added to the transaction.
+pub(crate) fn sign_offer_tx(offer_tx: &Transaction, network: Option<Network>) -> Result<String> {
+    let output = bitcoin_cli(
+        network,
+        &[
+            "signrawtransactionwithwallet",
+            &serialize_hex(offer_tx),
+            "[]",
+            "SINGLE|ANYONECANPAY",
+        ],
+    )?;
+    let signed: BSignedTx = serde_json::from_slice(&output)?;
+    Ok(signed.hex)
+}
+
+pub(crate) fn new_address(network: Option<Network>) -> Result<Address<NetworkUnchecked>> {
+    let output = bitcoin_cli(network, &["getnewaddress"])?;
+    Ok(String::from_utf8(output)?.trim().parse()?)
+}
+
+pub(crate) fn new_change_address(network: Option<Network>) -> Result<Address<NetworkUnchecked>> {
+    let output = bitcoin_cli(network, &["getrawchangeaddress"])?;
+    Ok(String::from_utf8(output)?.trim().parse()?)
+}
+
+pub(crate) fn funding_utxo_value(utxo: &OutPoint, network: Option<Network>) -> Result<u64> {
+    let output = bitcoin_cli(
+        network,
+        &["gettxout", &utxo.txid.to_string(), &utxo.vout.to_string()],
+    )?;
+    // nothing is printed for a spent or unknown output
+    let tx_out: BTxOut = serde_json::from_slice(&output)
+        .map_err(|_| anyhow!("funding UTXO {} is not found or already spent", utxo))?;
+    Ok((tx_out.value * 100000000f64).round() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bitcoin_cli_chain_arg() {
+        let args = |network| {
+            bitcoin_cli_command(network)
+                .get_args()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(args(None), Vec::<String>::new());
+        assert_eq!(args(Some(Network::Mainnet)), ["-chain=main"]);
+        assert_eq!(args(Some(Network::Testnet4)), ["-chain=testnet4"]);
+        assert_eq!(args(Some(Network::Regtest)), ["-chain=regtest"]);
+    }
 }
@@ -1,7 +1,9 @@
 pub mod app;
 pub mod cli;
+pub mod schema;
 pub mod script;
 pub mod spell;
+pub mod swap;
 pub mod tx;
 pub mod utils;
 
@@ -0,0 +1,118 @@
+//! JSON Schema of the spell source format, for editors to validate spells and for generating SDKs.
+
+use crate::spell::Spell;
+use schemars::generate::SchemaSettings;
+use serde_json::Value;
+
+/// Keys of apps in a spell: `$` followed by anything.
+pub(crate) const APP_KEY_PATTERN: &str = "^\\$.+$";
+/// Hex-encoded bytes.
+pub(crate) const HEX_PATTERN: &str = "^([0-9a-fA-F]{2})*$";
+
+/// JSON Schema (draft 2020-12) of [`Spell`], derived from its [`JsonSchema`](schemars::JsonSchema)
+/// implementation.
+pub fn spell_schema() -> Value {
+    SchemaSettings::draft2020_12()
+        .into_generator()
+        .into_root_schema_for::<Spell>()
+        .to_value()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::spell::Network;
+    use crate::spell::{Input, Output};
+    use charms_data::{App, Data, TxId, UtxoId, B32};
+    use regex::Regex;
+
+    fn properties(schema: &Value) -> Vec<String> {
+        let mut properties: Vec<String> = schema["properties"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        properties.sort();
+        properties
+    }
+
+    fn fields<T: serde::Serialize>(value: &T) -> Vec<String> {
+        let mut fields: Vec<String> = serde_json::to_value(value)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn schema_matches_types() {
+        let schema = spell_schema();
+
+        let app = App {
+            tag: 't',
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        let charms = [("$00".to_string(), Data::from(&1u64))].into();
+        let input = Input {
+            utxo_id: Some(UtxoId(TxId([3; 32]), 1)),
+            charms: Some(charms),
+            sequence: Some(0),
+        };
+        let output = Output {
+            address: Some(
+                "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+                    .parse()
+                    .unwrap(),
+            ),
+            script_pubkey: Some(Default::default()),
+            descriptor: Some("raw()".to_string()),
+            op_return: Some(vec![]),
+            sats: Some(1000),
+            charms: Some(Default::default()),
+        };
+        let spell = Spell {
+            apps: [("$00".to_string(), app.clone())].into(),
+            public_inputs: Some(Default::default()),
+            private_inputs: Some(Default::default()),
+            ins: vec![input.clone()],
+            refs: Some(vec![input.clone()]),
+            outs: vec![output.clone()],
+            lock_time: Some(0),
+            network: Some(Network::Testnet4),
+            ..Spell::new()
+        };
+        assert_eq!(properties(&schema), fields(&spell));
+        assert_eq!(properties(&schema["$defs"]["Input"]), fields(&input));
+        assert_eq!(properties(&schema["$defs"]["Output"]), fields(&output));
+
+        let pattern =
+            |def: &str| Regex::new(schema["$defs"][def]["pattern"].as_str().unwrap()).unwrap();
+        assert!(pattern("App").is_match(&app.to_string()));
+        assert!(pattern("UtxoId").is_match(&input.utxo_id.unwrap().to_string()));
+
+        let network = serde_json::to_value(Network::Testnet4).unwrap();
+        assert!(schema["$defs"]["Network"]["enum"]
+            .as_array()
+            .unwrap()
+            .contains(&network));
+    }
+
+    #[test]
+    fn defs_are_referenced() {
+        let schema = spell_schema();
+        let schema_str = schema.to_string();
+        for def in schema["$defs"].as_object().unwrap().keys() {
+            assert!(
+                schema_str.contains(&format!("\"#/$defs/{}\"", def)),
+                "{} is not referenced",
+                def
+            );
+        }
+    }
+}
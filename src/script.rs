@@ -1,45 +1,122 @@
 use bitcoin::{
     constants::MAX_SCRIPT_ELEMENT_SIZE,
     opcodes::{
-        all::{OP_CHECKSIG, OP_ENDIF, OP_IF},
+        all::{OP_CHECKSIG, OP_CSV, OP_DROP, OP_ENDIF, OP_IF},
         OP_FALSE,
     },
     script::{Builder, PushBytes},
     secp256k1::Secp256k1,
     taproot::{ControlBlock, LeafVersion, TaprootBuilder, TaprootSpendInfo},
-    ScriptBuf, XOnlyPublicKey,
+    ScriptBuf, Sequence, XOnlyPublicKey,
 };
+use charms_client::tx::SpellEncoding;
+use serde::{Deserialize, Serialize};
 
-pub fn control_block(public_key: XOnlyPublicKey, script: ScriptBuf) -> ControlBlock {
-    taproot_spend_info(public_key, script.clone())
+/// Refund path for the committed spell output, in case the spell transaction never confirms:
+/// a second Tapscript leaf spendable with `pubkey` once the output is `timeout` blocks old.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Refund {
+    /// Key the committed spell output is refunded to.
+    pub pubkey: XOnlyPublicKey,
+    /// Relative timelock (in blocks) of the refund path.
+    pub timeout: u16,
+}
+
+impl Refund {
+    /// Sequence number of the input spending the committed spell output via the refund path.
+    pub fn sequence(&self) -> Sequence {
+        Sequence::from_height(self.timeout)
+    }
+}
+
+pub fn control_block(
+    public_key: XOnlyPublicKey,
+    script: ScriptBuf,
+    refund: Option<&Refund>,
+) -> ControlBlock {
+    taproot_spend_info(public_key, script.clone(), refund)
         .control_block(&(script, LeafVersion::TapScript))
         .unwrap()
 }
 
-pub fn data_script(public_key: XOnlyPublicKey, data: &[u8]) -> ScriptBuf {
+/// Refund leaf script: `<timeout> OP_CSV OP_DROP <pubkey> OP_CHECKSIG`.
+pub fn refund_script(refund: &Refund) -> ScriptBuf {
+    Builder::new()
+        .push_sequence(refund.sequence())
+        .push_opcode(OP_CSV)
+        .push_opcode(OP_DROP)
+        .push_x_only_key(&refund.pubkey)
+        .push_opcode(OP_CHECKSIG)
+        .into_script()
+}
+
+pub fn data_script(public_key: XOnlyPublicKey, encoding: SpellEncoding, data: &[u8]) -> ScriptBuf {
     let builder = ScriptBuf::builder();
-    push_envelope(builder, data)
+    push_envelope(builder, encoding, data)
         .push_slice(public_key.serialize())
         .push_opcode(OP_CHECKSIG)
         .into_script()
 }
 
-fn push_envelope(builder: Builder, data: &[u8]) -> Builder {
+fn push_envelope(builder: Builder, encoding: SpellEncoding, data: &[u8]) -> Builder {
     let mut builder = builder
         .push_opcode(OP_FALSE)
         .push_opcode(OP_IF)
         .push_slice(b"spell");
+    if let Some(marker) = encoding.marker() {
+        builder = builder.push_slice([marker]);
+    }
     for chunk in data.chunks(MAX_SCRIPT_ELEMENT_SIZE) {
         builder = builder.push_slice::<&PushBytes>(chunk.try_into().unwrap());
     }
     builder.push_opcode(OP_ENDIF)
 }
 
-pub fn taproot_spend_info(public_key: XOnlyPublicKey, script: ScriptBuf) -> TaprootSpendInfo {
+/// Taproot tree of the committed spell output: the spell `script` leaf, alongside the refund leaf
+/// if there is a `refund` path.
+pub fn taproot_spend_info(
+    public_key: XOnlyPublicKey,
+    script: ScriptBuf,
+    refund: Option<&Refund>,
+) -> TaprootSpendInfo {
     let secp256k1 = Secp256k1::new();
-    TaprootBuilder::new()
-        .add_leaf(0, script)
-        .unwrap()
-        .finalize(&secp256k1, public_key)
-        .unwrap()
+    let builder = match refund {
+        None => TaprootBuilder::new().add_leaf(0, script),
+        Some(refund) => TaprootBuilder::new()
+            .add_leaf(1, script)
+            .and_then(|builder| builder.add_leaf(1, refund_script(refund))),
+    };
+    builder.unwrap().finalize(&secp256k1, public_key).unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::{rand::thread_rng, Keypair};
+
+    #[test]
+    fn spell_leaf_with_refund_leaf() {
+        let secp256k1 = Secp256k1::new();
+        let (public_key, _) = Keypair::new(&secp256k1, &mut thread_rng()).x_only_public_key();
+        let refund = Refund {
+            pubkey: public_key,
+            timeout: 144,
+        };
+        let script = data_script(public_key, SpellEncoding::Cbor, b"data");
+
+        let spell_cb = control_block(public_key, script.clone(), None);
+        assert!(spell_cb.merkle_branch.is_empty());
+
+        let spend_info = taproot_spend_info(public_key, script.clone(), Some(&refund));
+        let spell_cb = control_block(public_key, script.clone(), Some(&refund));
+        assert_eq!(spell_cb.merkle_branch.len(), 1);
+        assert!(spell_cb.verify_taproot_commitment(
+            &secp256k1,
+            spend_info.output_key().to_inner(),
+            &script
+        ));
+        assert!(spend_info
+            .control_block(&(refund_script(&refund), LeafVersion::TapScript))
+            .is_some());
+    }
 }
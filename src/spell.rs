@@ -1,9 +1,11 @@
 #[cfg(feature = "prover")]
 use crate::tx::add_spell;
 use crate::{
-    app, tx,
+    app,
+    schema::{APP_KEY_PATTERN, HEX_PATTERN},
+    script::Refund,
+    tx,
     tx::txs_by_txid,
-    utils,
     utils::{BoxedSP1Prover, Shared},
     SPELL_CHECKER_BINARY, SPELL_VK,
 };
@@ -14,64 +16,185 @@ use bitcoin::{
     address::NetworkUnchecked,
     consensus::encode::{deserialize_hex, serialize_hex},
     hashes::Hash,
-    Address, Amount, OutPoint,
+    Address, Amount, OutPoint, ScriptBuf, Sequence,
 };
+#[cfg(feature = "prover")]
+use charms_client::tx::{encode_spell_data, strip_prev_tx, CompactPrevTx};
 pub use charms_client::{
     to_tx, NormalizedCharms, NormalizedSpell, NormalizedTransaction, Proof, SpellProverInput,
     CURRENT_VERSION,
 };
+use charms_client::{DenormalizedInput, SpellFeatures};
 use charms_data::{util, App, Charms, Data, Transaction, TxId, UtxoId, B32};
 #[cfg(not(feature = "prover"))]
 use reqwest::Client;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use serde_with::{base64::Base64, serde_as, IfIsHumanReadable};
-use sp1_sdk::{SP1ProofMode, SP1Stdin};
+use serde_with::{base64::Base64, hex::Hex, serde_as, IfIsHumanReadable};
+use sp1_sdk::{SP1ProofMode, SP1ProvingKey, SP1Stdin, SP1VerifyingKey};
 use std::{
     collections::{BTreeMap, BTreeSet},
-    env,
+    fmt,
     sync::Arc,
 };
 
+/// Bitcoin network. Addresses in spells and prove requests are validated against it.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    #[default]
+    Testnet4,
+    Signet,
+    Regtest,
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Mainnet => f.write_str("mainnet"),
+            Network::Testnet4 => f.write_str("testnet4"),
+            Network::Signet => f.write_str("signet"),
+            Network::Regtest => f.write_str("regtest"),
+        }
+    }
+}
+
+impl From<Network> for bitcoin::Network {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Mainnet => bitcoin::Network::Bitcoin,
+            Network::Testnet4 => bitcoin::Network::Testnet4,
+            Network::Signet => bitcoin::Network::Signet,
+            Network::Regtest => bitcoin::Network::Regtest,
+        }
+    }
+}
+
+impl Network {
+    /// Make sure the address is valid for this network.
+    pub fn check_address(self, address: &Address<NetworkUnchecked>) -> anyhow::Result<Address> {
+        address.clone().require_network(self.into()).map_err(|_| {
+            anyhow!(
+                "address {} is not valid for network {}",
+                address.assume_checked_ref(),
+                self
+            )
+        })
+    }
+}
+
+/// Resolve the network from `(source, network)` pairs: all specified networks must be the same.
+/// Returns the default network if none is specified.
+pub fn resolve_network(settings: &[(&str, Option<Network>)]) -> anyhow::Result<Network> {
+    let mut resolved: Option<(&str, Network)> = None;
+    for &(source, network) in settings {
+        let Some(network) = network else {
+            continue;
+        };
+        match resolved {
+            Some((resolved_source, resolved_network)) => ensure!(
+                resolved_network == network,
+                "network mismatch: {} is for {}, but {} is for {}",
+                resolved_source,
+                resolved_network,
+                source,
+                network
+            ),
+            None => resolved = Some((source, network)),
+        }
+    }
+    Ok(resolved.map(|(_, network)| network).unwrap_or_default())
+}
+
 /// Charm as represented in a spell.
 /// Map of `$KEY: data`.
 pub type KeyedCharms = BTreeMap<String, Data>;
 
 /// UTXO as represented in a spell.
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct Input {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub utxo_id: Option<UtxoId>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(extend("propertyNames" = { "pattern": APP_KEY_PATTERN }))]
     pub charms: Option<KeyedCharms>,
+    /// Sequence number (`nSequence`) of the input, e.g. encoding a relative timelock.
+    /// Only used for transaction inputs (not reference inputs).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u32>,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// Transaction output as represented in a spell.
+/// Exactly one of `address`, `script_pubkey`, `descriptor` or `op_return` specifies where the
+/// output goes.
+#[serde_as]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[schemars(
+    deny_unknown_fields,
+    extend("oneOf" = [
+        { "required": ["address"] },
+        { "required": ["script_pubkey"] },
+        { "required": ["descriptor"] },
+        { "required": ["op_return"] },
+    ])
+)]
 pub struct Output {
+    /// Bitcoin address.
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>")]
     pub address: Option<Address<NetworkUnchecked>>,
+    /// Raw (hex-encoded) `script_pubkey`, for scripts that have no address form.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>", regex(pattern = HEX_PATTERN))]
+    pub script_pubkey: Option<ScriptBuf>,
+    /// Output descriptor (without wildcards), e.g. `raw(...)` or `tr(...)`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub descriptor: Option<String>,
+    /// (Hex-encoded) data to put in an `OP_RETURN` output. Such outputs can't have charms.
+    #[serde_as(as = "Option<Hex>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(with = "Option<String>", regex(pattern = HEX_PATTERN))]
+    pub op_return: Option<Vec<u8>>,
+    /// Amount of the output in sats.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub sats: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(extend("propertyNames" = { "pattern": APP_KEY_PATTERN }))]
     pub charms: Option<KeyedCharms>,
 }
 
+impl Output {
+    /// Check if this is an `OP_RETURN` output.
+    pub fn is_op_return(&self) -> bool {
+        self.op_return.is_some()
+    }
+}
+
 /// Defines how spells are represented in their source form and in CLI outputs,
 /// in both human-friendly (JSON/YAML) and machine-friendly (CBOR) formats.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
 pub struct Spell {
     /// Version of the protocol.
     pub version: u32,
 
     /// Apps used in the spell. Map of `$KEY: App`.
     /// Keys are arbitrary strings. They just need to be unique (inside the spell).
+    #[schemars(extend("propertyNames" = { "pattern": APP_KEY_PATTERN }))]
     pub apps: BTreeMap<String, App>,
 
     /// Public inputs to the apps for this spell. Map of `$KEY: Data`.
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(extend("propertyNames" = { "pattern": APP_KEY_PATTERN }))]
     pub public_inputs: Option<BTreeMap<String, Data>>,
 
     /// Private inputs to the apps for this spell. Map of `$KEY: Data`.
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[schemars(extend("propertyNames" = { "pattern": APP_KEY_PATTERN }))]
     pub private_inputs: Option<BTreeMap<String, Data>>,
 
     /// Transaction inputs.
@@ -81,6 +204,14 @@ pub struct Spell {
     pub refs: Option<Vec<Input>>,
     /// Transaction outputs.
     pub outs: Vec<Output>,
+
+    /// Absolute lock time (`nLockTime`) of the transaction: block height or UNIX timestamp.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lock_time: Option<u32>,
+
+    /// Bitcoin network the spell is for. Output addresses are validated against it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network>,
 }
 
 impl Spell {
@@ -94,10 +225,22 @@ impl Spell {
             ins: vec![],
             refs: None,
             outs: vec![],
+            lock_time: None,
+            network: None,
         }
     }
 
-    /// Get a [`Transaction`] for the spell.
+    /// Bitcoin network the spell is for (the default network if not specified).
+    pub fn network(&self) -> Network {
+        self.network.unwrap_or_default()
+    }
+
+    /// Sequence number for inputs that don't specify one.
+    pub fn default_sequence(&self) -> Sequence {
+        default_sequence(self.lock_time)
+    }
+
+    /// Get a [`Transaction`] for the spell (e.g. to run app contracts on a spell draft).
     pub fn to_tx(&self) -> anyhow::Result<Transaction> {
         let ins = self.strings_of_charms(&self.ins)?;
         let empty_vec = vec![];
@@ -105,7 +248,10 @@ impl Spell {
         let outs = self
             .outs
             .iter()
-            .map(|output| self.charms(&output.charms))
+            .map(|output| match output.is_op_return() {
+                true => Ok(Charms::new()),
+                false => self.charms(&output.charms),
+            })
             .collect::<Result<_, _>>()?;
 
         Ok(Transaction { ins, refs, outs })
@@ -168,6 +314,14 @@ impl Spell {
             .collect::<Result<_, _>>()?;
         ensure!(refs.len() == self_refs.len(), "duplicate reference inputs");
 
+        ensure!(
+            self.outs
+                .iter()
+                .filter(|u| u.is_op_return())
+                .all(|u| u.charms.as_ref().is_none_or(|charms| charms.is_empty())),
+            "charms are not allowed on OP_RETURN outputs"
+        );
+
         let empty_charm = KeyedCharms::new();
 
         let outs: Vec<NormalizedCharms> = self
@@ -203,89 +357,120 @@ impl Spell {
         Ok((norm_spell, app_private_inputs))
     }
 
-    /// De-normalize a normalized spell.
+    /// De-normalize a normalized spell (see [`charms_client::denormalized`]), keying the apps
+    /// found in `app_keys` by their keys there (see [`Spell::with_app_keys`]).
     #[tracing::instrument(level = "debug", skip_all)]
-    pub fn denormalized(norm_spell: &NormalizedSpell) -> Self {
-        let apps = (0..)
-            .zip(norm_spell.app_public_inputs.keys())
-            .map(|(i, app)| (utils::str_index(&i), app.clone()))
-            .collect();
-
-        let public_inputs = match (0..)
-            .zip(norm_spell.app_public_inputs.values())
-            .filter_map(|(i, data)| match data {
-                data if data.is_empty() => None,
-                data => Some((
-                    utils::str_index(&i),
-                    data.value().ok().expect("Data should be a Value"),
-                )),
-            })
-            .collect::<BTreeMap<_, _>>()
-        {
-            map if map.is_empty() => None,
-            map => Some(map),
+    pub fn denormalized(norm_spell: &NormalizedSpell, app_keys: &BTreeMap<App, String>) -> Self {
+        let spell = charms_client::denormalized(norm_spell);
+        let input = |input: DenormalizedInput| Input {
+            utxo_id: Some(input.utxo_id),
+            charms: None,
+            sequence: None,
         };
+        Self {
+            version: spell.version,
+            apps: spell.apps,
+            public_inputs: spell.public_inputs,
+            private_inputs: None,
+            ins: spell.ins.into_iter().map(input).collect(),
+            refs: spell.refs.map(|refs| refs.into_iter().map(input).collect()),
+            outs: spell
+                .outs
+                .into_iter()
+                .map(|output| Output {
+                    charms: output.charms,
+                    ..Default::default()
+                })
+                .collect(),
+            lock_time: None,
+            network: None,
+        }
+        .with_app_keys(app_keys)
+    }
 
-        let Some(norm_spell_ins) = &norm_spell.tx.ins else {
-            unreachable!("spell must have inputs");
-        };
-        let ins = norm_spell_ins
+    /// Rename app keys to `app_keys` (e.g. human-readable aliases like `$TOAD`), for the apps
+    /// found there. Keys the spell already uses are not reassigned.
+    pub fn with_app_keys(self, app_keys: &BTreeMap<App, String>) -> Self {
+        let renames: BTreeMap<String, String> = self
+            .apps
             .iter()
-            .map(|utxo_id| Input {
-                utxo_id: Some(utxo_id.clone()),
-                charms: None,
+            .filter_map(|(k, app)| {
+                app_keys
+                    .get(app)
+                    .filter(|new_k| !self.apps.contains_key(*new_k))
+                    .map(|new_k| (k.clone(), new_k.clone()))
             })
             .collect();
+        if renames.is_empty() {
+            return self;
+        }
 
-        let refs = match norm_spell
-            .tx
-            .refs
-            .iter()
-            .map(|utxo_id| Input {
-                utxo_id: Some(utxo_id.clone()),
-                charms: None,
-            })
-            .collect::<Vec<_>>()
-        {
-            refs if refs.is_empty() => None,
-            refs => Some(refs),
+        let rename_inputs = |inputs: Vec<Input>| -> Vec<Input> {
+            inputs
+                .into_iter()
+                .map(|input| Input {
+                    charms: input.charms.map(|charms| rename_keys(charms, &renames)),
+                    ..input
+                })
+                .collect()
         };
+        Self {
+            apps: rename_keys(self.apps, &renames),
+            public_inputs: self.public_inputs.map(|m| rename_keys(m, &renames)),
+            private_inputs: self.private_inputs.map(|m| rename_keys(m, &renames)),
+            ins: rename_inputs(self.ins),
+            refs: self.refs.map(rename_inputs),
+            outs: self
+                .outs
+                .into_iter()
+                .map(|output| Output {
+                    charms: output.charms.map(|charms| rename_keys(charms, &renames)),
+                    ..output
+                })
+                .collect(),
+            ..self
+        }
+    }
 
-        let outs = norm_spell
-            .tx
-            .outs
+    /// Declare apps from `keyed_apps` (e.g. registered aliases) for the keys the spell uses but
+    /// doesn't have in `apps`.
+    pub fn add_apps(&mut self, keyed_apps: &BTreeMap<String, App>) {
+        let used_keys: BTreeSet<String> = self
+            .ins
             .iter()
-            .map(|n_charms| Output {
-                address: None,
-                sats: None,
-                charms: match n_charms
-                    .iter()
-                    .map(|(i, data)| {
-                        (
-                            utils::str_index(i),
-                            data.value().ok().expect("Data should be a Value"),
-                        )
-                    })
-                    .collect::<KeyedCharms>()
-                {
-                    charms if charms.is_empty() => None,
-                    charms => Some(charms),
-                },
-            })
+            .chain(self.refs.iter().flatten())
+            .filter_map(|input| input.charms.as_ref())
+            .chain(self.outs.iter().filter_map(|output| output.charms.as_ref()))
+            .chain(self.public_inputs.as_ref())
+            .chain(self.private_inputs.as_ref())
+            .flat_map(|m| m.keys().cloned())
             .collect();
-
-        Self {
-            version: norm_spell.version,
-            apps,
-            public_inputs,
-            private_inputs: None,
-            ins,
-            refs,
-            outs,
+        for k in used_keys {
+            if let (false, Some(app)) = (self.apps.contains_key(&k), keyed_apps.get(&k)) {
+                self.apps.insert(k, app.clone());
+            }
         }
     }
 }
 
+fn rename_keys<V>(
+    m: BTreeMap<String, V>,
+    renames: &BTreeMap<String, String>,
+) -> BTreeMap<String, V> {
+    m.into_iter()
+        .map(|(k, v)| (renames.get(&k).cloned().unwrap_or(k), v))
+        .collect()
+}
+
+/// Sequence number for inputs that don't specify one: final, unless the transaction has a lock
+/// time, which is only enforced if at least one input is not final.
+fn default_sequence(lock_time: Option<u32>) -> Sequence {
+    match lock_time {
+        Some(_) => Sequence::ENABLE_LOCKTIME_NO_RBF,
+        None => Sequence::MAX,
+    }
+}
+
 fn app_inputs(
     keyed_apps: &BTreeMap<String, App>,
     keyed_inputs: &BTreeMap<String, Data>,
@@ -326,6 +511,72 @@ impl Prove for Prover {
         app_private_inputs: BTreeMap<App, Data>,
         prev_txs: Vec<bitcoin::Transaction>,
         _expected_cycles: Option<Vec<u64>>,
+    ) -> anyhow::Result<(NormalizedSpell, Proof, u64)> {
+        let keys = self.setup_keys(app_binaries);
+        self.prove_with_keys(&keys, norm_spell, app_private_inputs, prev_txs)
+    }
+}
+
+/// Proving keys of the spell checker and of app contracts. Setting them up is expensive, so they
+/// are set up once per proving session (e.g. for a batch of spells).
+#[derive(Clone)]
+pub struct ProvingKeys {
+    spell_checker: Arc<SP1ProvingKey>,
+    apps: BTreeMap<B32, Arc<(SP1ProvingKey, SP1VerifyingKey)>>,
+}
+
+impl ProvingKeys {
+    /// Keys for proving a spell with the apps in `app_binaries` only.
+    pub fn for_binaries(&self, app_binaries: &BTreeMap<B32, Vec<u8>>) -> Self {
+        Self {
+            spell_checker: self.spell_checker.clone(),
+            apps: with_binaries(&self.apps, app_binaries),
+        }
+    }
+}
+
+/// Entries of `by_vk` for the apps in `app_binaries` only.
+fn with_binaries<V: Clone>(
+    by_vk: &BTreeMap<B32, V>,
+    app_binaries: &BTreeMap<B32, Vec<u8>>,
+) -> BTreeMap<B32, V> {
+    by_vk
+        .iter()
+        .filter(|(vk, _)| app_binaries.contains_key(vk))
+        .map(|(vk, v)| (vk.clone(), v.clone()))
+        .collect()
+}
+
+/// Collect the results for a batch of spells. The first error fails the whole batch (no partial
+/// results): it is reported with the index of the spell.
+fn batch_results<T>(
+    results: impl IntoIterator<Item = anyhow::Result<T>>,
+) -> anyhow::Result<Vec<T>> {
+    results
+        .into_iter()
+        .enumerate()
+        .map(|(i, result)| result.map_err(|e| anyhow!("spell {}: {}", i, e)))
+        .collect()
+}
+
+impl Prover {
+    /// Set up the proving keys of the spell checker and of the apps in `app_binaries`.
+    pub fn setup_keys(&self, app_binaries: &BTreeMap<B32, Vec<u8>>) -> ProvingKeys {
+        let (spell_checker, _) = self.sp1_client.get().setup(SPELL_CHECKER_BINARY);
+        ProvingKeys {
+            spell_checker: Arc::new(spell_checker),
+            apps: self.app_prover.setup(app_binaries),
+        }
+    }
+
+    /// Prove a spell with proving keys set up by [`Prover::setup_keys`]: see [`Prove::prove`].
+    /// Apps without keys are not proven (they must be simple transfers).
+    pub fn prove_with_keys(
+        &self,
+        keys: &ProvingKeys,
+        norm_spell: NormalizedSpell,
+        app_private_inputs: BTreeMap<App, Data>,
+        prev_txs: Vec<bitcoin::Transaction>,
     ) -> anyhow::Result<(NormalizedSpell, Proof, u64)> {
         let mut stdin = SP1Stdin::new();
 
@@ -335,11 +586,18 @@ impl Prove for Prover {
             .app_public_inputs
             .iter()
             .zip(0usize..)
-            .filter_map(|((app, _), i)| app_binaries.get(&app.vk).map(|_| i))
+            .filter_map(|((app, _), i)| keys.apps.contains_key(&app.vk).then_some(i))
             .collect();
+        // the spell checker only needs the witness data of the spells it verifies
+        let (stripped_prev_txs, compact_prev_txs) =
+            match SpellFeatures::of(norm_spell.version).compact_prev_txs {
+                true => (vec![], prev_txs.iter().map(CompactPrevTx::new).collect()),
+                false => (prev_txs.iter().map(strip_prev_tx).collect(), vec![]),
+            };
         let prover_input = SpellProverInput {
             self_spell_vk: SPELL_VK.to_string(),
-            prev_txs,
+            prev_txs: stripped_prev_txs,
+            compact_prev_txs,
             spell: norm_spell.clone(),
             app_contract_proofs,
         };
@@ -365,24 +623,23 @@ impl Prove for Prover {
         // }
 
         self.app_prover.prove(
-            app_binaries,
+            &keys.apps,
             tx,
             app_public_inputs,
             app_private_inputs,
             &mut stdin,
         )?;
 
-        let (pk, _) = self.sp1_client.get().setup(SPELL_CHECKER_BINARY);
         // TODO find a way to get cycles count from the prover, remove this
         let (_, report) = self
             .sp1_client
             .get()
             .execute(SPELL_CHECKER_BINARY, &stdin)?;
 
-        let proof = self
-            .sp1_client
-            .get()
-            .prove(&pk, &stdin, SP1ProofMode::Groth16)?;
+        let proof =
+            self.sp1_client
+                .get()
+                .prove(&keys.spell_checker, &stdin, SP1ProofMode::Groth16)?;
         let proof = proof.bytes().into_boxed_slice();
 
         let mut norm_spell2 = norm_spell;
@@ -395,6 +652,7 @@ impl Prove for Prover {
 #[cfg(test)]
 mod test {
     use super::*;
+    use charms_client::V3;
 
     #[test]
     fn deserialize_keyed_charm() {
@@ -416,6 +674,207 @@ $TOAD: 9
         let utxo_id: UtxoId = utxo_id_data.value().unwrap();
         assert_eq!(utxo_id_0, dbg!(utxo_id));
     }
+
+    #[test]
+    fn op_return_and_raw_script_outputs() {
+        let y = r#"
+- op_return: 6368726d73
+- script_pubkey: 5120aa8b6f3b0e2a0c2a6b4bbdd5b0d4c1b6d0c2a8e8e3f1a6d7b9c0d1e2f3a4b5c6
+  charms:
+    $00: 1
+- script_pubkey: 0014751e76e8199196d454941c45d1b3a323f1433bd6
+  op_return: 00
+"#;
+        let outs: Vec<Output> = serde_yaml::from_str(y).unwrap();
+
+        let tx_outs = tx::tx_output(&outs[..2], Network::Testnet4).unwrap();
+        assert!(tx_outs[0].script_pubkey.is_op_return());
+        assert_eq!(tx_outs[0].value, Amount::ZERO);
+        assert!(tx_outs[1].script_pubkey.is_p2tr());
+        assert_eq!(tx_outs[1].value, Amount::from_sat(1000));
+
+        assert!(tx::tx_output(&outs[2..], Network::Testnet4).is_err());
+
+        let mut spell = Spell::new();
+        spell.outs = vec![Output {
+            op_return: Some(b"charms".to_vec()),
+            charms: Some([("$00".to_string(), Data::from(&1u64))].into()),
+            ..Default::default()
+        }];
+        spell.apps = [("$00".to_string(), App::default())].into();
+        assert!(spell.normalized().is_err());
+    }
+
+    #[test]
+    fn lock_time_and_sequences() {
+        let y = r#"
+version: 2
+apps: {}
+ins:
+  - utxo_id: f72700ac56bd4dd61f2ccb4acdf21d0b11bb294fc3efa9012b77903932197d2f:2
+  - utxo_id: f72700ac56bd4dd61f2ccb4acdf21d0b11bb294fc3efa9012b77903932197d2f:3
+    sequence: 144
+outs: []
+lock_time: 900000
+"#;
+        let spell: Spell = serde_yaml::from_str(y).unwrap();
+
+        let tx = tx::from_spell(&spell).unwrap();
+        assert_eq!(tx.lock_time.to_consensus_u32(), 900000);
+        assert_eq!(tx.input[0].sequence, Sequence::ENABLE_LOCKTIME_NO_RBF);
+        assert_eq!(tx.input[1].sequence, Sequence(144));
+
+        let spell = Spell {
+            lock_time: None,
+            ..spell
+        };
+        let tx = tx::from_spell(&spell).unwrap();
+        assert_eq!(tx.lock_time.to_consensus_u32(), 0);
+        assert_eq!(tx.input[0].sequence, Sequence::MAX);
+        assert_eq!(tx.input[1].sequence, Sequence(144));
+    }
+
+    #[test]
+    fn network_address_validation() {
+        let address: Address<NetworkUnchecked> = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+            .parse()
+            .unwrap();
+        assert!(Network::Mainnet.check_address(&address).is_ok());
+        assert!(Network::Testnet4.check_address(&address).is_err());
+
+        let output = Output {
+            address: Some(address),
+            ..Default::default()
+        };
+        assert!(tx::output_script_pubkey(&output, Network::Mainnet).is_ok());
+        assert!(tx::output_script_pubkey(&output, Network::Regtest).is_err());
+
+        assert_eq!(
+            resolve_network(&[("--network", None)]).unwrap(),
+            Network::Testnet4
+        );
+        assert_eq!(
+            resolve_network(&[("--network", None), ("spell", Some(Network::Signet))]).unwrap(),
+            Network::Signet
+        );
+        assert!(resolve_network(&[
+            ("--network", Some(Network::Mainnet)),
+            ("spell", Some(Network::Testnet4))
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn keys_for_binaries() {
+        let keys: BTreeMap<B32, u32> = [(B32([1; 32]), 1), (B32([2; 32]), 2)].into();
+        let app_binaries: BTreeMap<B32, Vec<u8>> =
+            [(B32([2; 32]), vec![]), (B32([3; 32]), vec![])].into();
+        assert_eq!(
+            with_binaries(&keys, &app_binaries),
+            [(B32([2; 32]), 2)].into()
+        );
+        assert!(with_binaries(&keys, &BTreeMap::new()).is_empty());
+    }
+
+    #[test]
+    fn batch_fails_with_spell_index() {
+        let results = vec![Ok(0), Ok(1)];
+        assert_eq!(batch_results(results).unwrap(), vec![0, 1]);
+
+        let results = vec![
+            Ok(0),
+            Err(anyhow!("bad spell")),
+            Err(anyhow!("worse spell")),
+        ];
+        assert_eq!(
+            batch_results(results).unwrap_err().to_string(),
+            "spell 1: bad spell"
+        );
+
+        // spells after the failing one are not even tried
+        let mut tried = vec![];
+        let results = (0..3).map(|i| {
+            tried.push(i);
+            match i {
+                0 => Err(anyhow!("bad spell")),
+                _ => Ok(i),
+            }
+        });
+        assert!(batch_results(results).is_err());
+        assert_eq!(tried, vec![0]);
+    }
+
+    #[test]
+    fn refund_needs_taproot_tree() {
+        let mut prove_request = ProveRequest {
+            spell: Spell::new(),
+            binaries: BTreeMap::new(),
+            prev_txs: vec![],
+            funding_utxo: OutPoint::null(),
+            funding_utxo_value: 10000,
+            change_address: "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+                .parse()
+                .unwrap(),
+            fee_rate: 2.0,
+            charms_fee: None,
+            network: None,
+            refund: None,
+        };
+        assert!(prove_request.check_refund().is_ok());
+
+        prove_request.refund = Some(Refund {
+            pubkey: "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap(),
+            timeout: 144,
+        });
+        assert!(prove_request.check_refund().is_err());
+
+        prove_request.spell.version = V3;
+        assert!(prove_request.check_refund().is_ok());
+    }
+
+    #[test]
+    fn app_alias_keys() {
+        let toad = App {
+            tag: 't',
+            identity: B32([1; 32]),
+            vk: B32([2; 32]),
+        };
+        let nft = App {
+            tag: 'n',
+            ..App::default()
+        };
+
+        let y = r#"
+version: 3
+apps:
+  $01: n/0000000000000000000000000000000000000000000000000000000000000000/0000000000000000000000000000000000000000000000000000000000000000
+ins:
+  - utxo_id: f72700ac56bd4dd61f2ccb4acdf21d0b11bb294fc3efa9012b77903932197d2f:2
+    charms:
+      $TOAD: 10
+outs:
+  - script_pubkey: 0014751e76e8199196d454941c45d1b3a323f1433bd6
+    charms:
+      $TOAD: 10
+      $01: 1
+"#;
+        let mut spell: Spell = serde_yaml::from_str(y).unwrap();
+        spell.add_apps(&[("$TOAD".to_string(), toad.clone())].into());
+        assert_eq!(spell.apps.get("$TOAD"), Some(&toad));
+        assert_eq!(spell.apps.get("$01"), Some(&nft));
+
+        let (norm_spell, _) = spell.normalized().unwrap();
+        let app_keys = [(toad.clone(), "$TOAD".to_string())].into();
+        let spell2 = Spell::denormalized(&norm_spell, &app_keys);
+        assert_eq!(spell2.apps.get("$TOAD"), Some(&toad));
+        assert_eq!(spell2.outs[0].charms.as_ref().unwrap().len(), 2);
+        assert_eq!(
+            spell2.outs[0].charms.as_ref().unwrap().get("$TOAD"),
+            Some(&Data::from(&10u64))
+        );
+    }
 }
 
 pub trait ProveSpellTx {
@@ -423,6 +882,18 @@ pub trait ProveSpellTx {
         &self,
         prove_request: ProveRequest,
     ) -> impl std::future::Future<Output = anyhow::Result<[bitcoin::Transaction; 2]>>;
+
+    /// Prove a batch of independent spells in one proving session.
+    /// Returns the `[commit_tx, spell_tx]` pair of each spell, in order.
+    ///
+    /// The session only saves setting up the spell checker and the apps more than once: each
+    /// spell is still proven on its own, with its own Groth16 proof (to be verifiable on-chain).
+    /// If any spell fails, so does the whole batch: no transactions are returned, and the error
+    /// names the index of the failing spell.
+    fn prove_spell_txs(
+        &self,
+        prove_requests: Vec<ProveRequest>,
+    ) -> impl std::future::Future<Output = anyhow::Result<Vec<[bitcoin::Transaction; 2]>>>;
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -452,6 +923,44 @@ pub struct ProveRequest {
     pub change_address: Address<NetworkUnchecked>,
     pub fee_rate: f64,
     pub charms_fee: Option<CharmsFee>,
+    /// Bitcoin network the request is for. Must match the spell's network, if it specifies one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network>,
+    /// Refund path for the committed spell output, in case the spell transaction never confirms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refund: Option<Refund>,
+}
+
+impl ProveRequest {
+    /// Resolve the network the request is for (also setting it on the request and the spell) and
+    /// make sure the request's addresses are valid for it.
+    pub fn check_network(&mut self) -> anyhow::Result<Network> {
+        let network = resolve_network(&[("request", self.network), ("spell", self.spell.network)])?;
+        self.network = Some(network);
+        self.spell.network = Some(network);
+
+        network
+            .check_address(&self.change_address)
+            .map_err(|e| anyhow!("change address: {}", e))?;
+        if let Some(charms_fee) = &self.charms_fee {
+            network
+                .check_address(&charms_fee.fee_address)
+                .map_err(|e| anyhow!("charms fee address: {}", e))?;
+        }
+        Ok(network)
+    }
+
+    /// Make sure the spell's version supports the refund path, if the request has one: with it,
+    /// the spell script is a leaf of a two-leaf Taproot tree, and spells of earlier versions in
+    /// such a tree can't be extracted from the transaction (its charms would be lost).
+    pub fn check_refund(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.refund.is_none() || SpellFeatures::of(self.spell.version).taproot_tree,
+            "spell version {} does not support a refund path",
+            self.spell.version
+        );
+        Ok(())
+    }
 }
 
 pub struct Prover {
@@ -467,7 +976,99 @@ impl ProveSpellTx for Prover {
     #[cfg(feature = "prover")]
     async fn prove_spell_tx(
         &self,
-        ProveRequest {
+        prove_request: ProveRequest,
+    ) -> anyhow::Result<[bitcoin::Transaction; 2]> {
+        let keys = self.setup_keys(&prove_request.binaries);
+        self.prove_spell_tx_with_keys(prove_request, &keys)
+    }
+
+    #[cfg(feature = "prover")]
+    #[tracing::instrument(level = "info", skip_all, fields(num_spells = prove_requests.len()))]
+    async fn prove_spell_txs(
+        &self,
+        prove_requests: Vec<ProveRequest>,
+    ) -> anyhow::Result<Vec<[bitcoin::Transaction; 2]>> {
+        // check the requests first: not to prove any spell of a batch that fails anyway
+        let prove_requests = batch_results(prove_requests.into_iter().map(
+            |mut prove_request| -> anyhow::Result<_> {
+                prove_request.check_network()?;
+                prove_request.check_refund()?;
+                Ok(prove_request)
+            },
+        ))?;
+
+        // set up each app (and the spell checker) once for the whole batch
+        let binaries = prove_requests
+            .iter()
+            .flat_map(|prove_request| prove_request.binaries.clone())
+            .collect();
+        let keys = self.setup_keys(&binaries);
+
+        batch_results(prove_requests.into_iter().map(|prove_request| {
+            // only prove the apps the request has binaries for
+            let keys = keys.for_binaries(&prove_request.binaries);
+            self.prove_spell_tx_with_keys(prove_request, &keys)
+        }))
+    }
+
+    #[cfg(not(feature = "prover"))]
+    #[tracing::instrument(level = "info", skip_all)]
+    async fn prove_spell_tx(
+        &self,
+        prove_request: ProveRequest,
+    ) -> anyhow::Result<[bitcoin::Transaction; 2]> {
+        let prove_request = self.check_prove_request(prove_request)?;
+
+        let client = &self.client;
+        let response = client
+            .post(&self.charms_prove_api_url)
+            .json(&prove_request)
+            .send()
+            .await?;
+        let [commit_tx, spell_tx]: [String; 2] = response.json().await?;
+        let transactions = [deserialize_hex(&commit_tx)?, deserialize_hex(&spell_tx)?];
+        Ok(transactions)
+    }
+
+    #[cfg(not(feature = "prover"))]
+    #[tracing::instrument(level = "info", skip_all, fields(num_spells = prove_requests.len()))]
+    async fn prove_spell_txs(
+        &self,
+        prove_requests: Vec<ProveRequest>,
+    ) -> anyhow::Result<Vec<[bitcoin::Transaction; 2]>> {
+        let prove_requests = batch_results(
+            prove_requests
+                .into_iter()
+                .map(|prove_request| self.check_prove_request(prove_request)),
+        )?;
+
+        let client = &self.client;
+        let response = client
+            .post(format!("{}/batch", self.charms_prove_api_url))
+            .json(&prove_requests)
+            .send()
+            .await?;
+        let tx_pairs: Vec<[String; 2]> = response.json().await?;
+        tx_pairs
+            .iter()
+            .map(|[commit_tx, spell_tx]| {
+                Ok([deserialize_hex(commit_tx)?, deserialize_hex(spell_tx)?])
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "prover")]
+impl Prover {
+    fn prove_spell_tx_with_keys(
+        &self,
+        prove_request: ProveRequest,
+        keys: &ProvingKeys,
+    ) -> anyhow::Result<[bitcoin::Transaction; 2]> {
+        let mut prove_request = prove_request;
+        let network = prove_request.check_network()?;
+        prove_request.check_refund()?;
+        let ProveRequest {
             spell,
             binaries,
             prev_txs,
@@ -476,11 +1077,13 @@ impl ProveSpellTx for Prover {
             change_address,
             fee_rate,
             charms_fee,
-        }: ProveRequest,
-    ) -> anyhow::Result<[bitcoin::Transaction; 2]> {
+            refund,
+            ..
+        } = prove_request;
+
         let prev_txs_by_id = txs_by_txid(prev_txs.clone());
 
-        let tx = tx::from_spell(&spell);
+        let tx = tx::from_spell(&spell)?;
         ensure!(tx
             .input
             .iter()
@@ -500,13 +1103,8 @@ impl ProveSpellTx for Prover {
         )?;
         let total_app_cycles: u64 = expected_cycles.iter().sum();
 
-        let (norm_spell, proof, spell_cycles) = self.prove(
-            norm_spell,
-            &binaries,
-            app_private_inputs,
-            prev_txs.clone(),
-            Some(expected_cycles),
-        )?;
+        let (norm_spell, proof, spell_cycles) =
+            self.prove_with_keys(keys, norm_spell, app_private_inputs, prev_txs.clone())?;
 
         tracing::info!(
             "proof generated. total app cycles: {}, spell cycles: {}",
@@ -514,15 +1112,16 @@ impl ProveSpellTx for Prover {
             spell_cycles,
         );
 
-        // Serialize spell into CBOR
-        let spell_data = util::write(&(&norm_spell, &proof))?;
+        // Serialize spell into CBOR, compressed if that makes it smaller
+        let (spell_encoding, spell_data) = encode_spell_data(&norm_spell, &proof);
 
         // Parse change address into ScriptPubkey
-        let change_pubkey = change_address.assume_checked().script_pubkey();
+        let change_pubkey = network.check_address(&change_address)?.script_pubkey();
 
         let charms_fee_pubkey = charms_fee
-            .clone()
-            .map(|fee| fee.fee_address.assume_checked().script_pubkey());
+            .as_ref()
+            .map(|fee| Ok::<_, Error>(network.check_address(&fee.fee_address)?.script_pubkey()))
+            .transpose()?;
 
         // Calculate fee
         let charms_fee = get_charms_fee(charms_fee, total_app_cycles, spell_cycles);
@@ -534,6 +1133,7 @@ impl ProveSpellTx for Prover {
         let transactions = add_spell(
             tx,
             &spell_data,
+            spell_encoding,
             funding_utxo,
             Amount::from_sat(funding_utxo_value),
             change_pubkey,
@@ -541,20 +1141,29 @@ impl ProveSpellTx for Prover {
             &prev_txs_by_id,
             charms_fee_pubkey,
             charms_fee,
+            refund,
         );
         Ok(transactions)
     }
+}
 
+impl Prover {
     #[cfg(not(feature = "prover"))]
-    #[tracing::instrument(level = "info", skip_all)]
-    async fn prove_spell_tx(
-        &self,
-        prove_request: ProveRequest,
-    ) -> anyhow::Result<[bitcoin::Transaction; 2]> {
-        let prove_request = self.add_fee(prove_request);
+    fn add_fee(&self, prove_request: ProveRequest) -> ProveRequest {
+        let mut prove_request = prove_request;
+        prove_request.charms_fee = self.charms_fee_settings.clone();
+        prove_request
+    }
+
+    /// Add the Charms fee to the request and check it before sending it to the proving service.
+    #[cfg(not(feature = "prover"))]
+    fn check_prove_request(&self, prove_request: ProveRequest) -> anyhow::Result<ProveRequest> {
+        let mut prove_request = self.add_fee(prove_request);
+        prove_request.check_network()?;
+        prove_request.check_refund()?;
         let prev_txs_by_id = txs_by_txid(prove_request.prev_txs.clone());
 
-        let tx = tx::from_spell(&prove_request.spell);
+        let tx = tx::from_spell(&prove_request.spell)?;
         ensure!(tx
             .input
             .iter()
@@ -605,24 +1214,7 @@ impl ProveSpellTx for Prover {
             charms_fee
         );
 
-        let client = &self.client;
-        let response = client
-            .post(&self.charms_prove_api_url)
-            .json(&prove_request)
-            .send()
-            .await?;
-        let [commit_tx, spell_tx]: [String; 2] = response.json().await?;
-        let transactions = [deserialize_hex(&commit_tx)?, deserialize_hex(&spell_tx)?];
-        Ok(transactions)
-    }
-}
-
-impl Prover {
-    #[cfg(not(feature = "prover"))]
-    fn add_fee(&self, prove_request: ProveRequest) -> ProveRequest {
-        let mut prove_request = prove_request;
-        prove_request.charms_fee = self.charms_fee_settings.clone();
-        prove_request
+        Ok(prove_request)
     }
 }
 
@@ -633,27 +1225,15 @@ fn get_charms_fee(
 ) -> Amount {
     charms_fee
         .as_ref()
-        .map(|_| {
+        .map(|charms_fee| {
             Amount::from_sat(
-                (total_app_cycles + spell_cycles) * fee_sats_per_megacycle() / 1000000
-                    + fee_sats_base(),
+                (total_app_cycles + spell_cycles) * charms_fee.fee_rate / 1000000
+                    + charms_fee.fee_base,
             )
         })
         .unwrap_or_default()
 }
 
-fn fee_sats_per_megacycle() -> u64 {
-    env::var("CHARMS_FEE_RATE")
-        .map(|s| s.parse::<u64>().unwrap())
-        .unwrap_or(1000)
-}
-
-fn fee_sats_base() -> u64 {
-    env::var("CHARMS_FEE_BASE")
-        .map(|s| s.parse::<u64>().unwrap())
-        .unwrap_or(1000)
-}
-
 pub fn align_spell_to_tx(
     norm_spell: NormalizedSpell,
     tx: &bitcoin::Transaction,
@@ -0,0 +1,246 @@
+//! Atomic swaps of charms for BTC.
+//!
+//! The *maker* offers a UTXO with charms for a price in sats. The *offer* is a transaction
+//! spending the UTXO to an output paying the price to the maker, its only input signed
+//! `SIGHASH_SINGLE|ANYONECANPAY`: the signature commits only to the input and the output at the
+//! same index.
+//!
+//! The *taker* completes the offer with a spell transferring the charms to the taker: the offered
+//! UTXO is the first input and the payment to the maker is the first output of the spell
+//! transaction, the taker funds the rest. The *committed spell* input appended by
+//! [`tx::add_spell`] is signed `SIGHASH_ALL|ANYONECANPAY`, so the maker's signature stays valid.
+//!
+//! [`tx::add_spell`]: crate::tx::add_spell
+
+use crate::spell::{Input, Output, Spell};
+use anyhow::{anyhow, ensure, Result};
+use bitcoin::{
+    absolute::LockTime, ecdsa, hashes::Hash, taproot, transaction::Version, Amount, OutPoint,
+    ScriptBuf, Sequence, TapSighashType, Transaction, TxIn, TxOut,
+};
+use charms_data::{TxId, UtxoId};
+
+/// Create the (unsigned) offer transaction: spending `utxo` and paying `price` to
+/// `payment_script_pubkey`.
+pub fn offer_tx(utxo: OutPoint, price: Amount, payment_script_pubkey: ScriptBuf) -> Transaction {
+    Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: utxo,
+            script_sig: Default::default(),
+            sequence: Sequence::MAX,
+            witness: Default::default(),
+        }],
+        output: vec![TxOut {
+            value: price,
+            script_pubkey: payment_script_pubkey,
+        }],
+    }
+}
+
+/// Check `offer_tx` is an offer (as created by [`offer_tx`]) signed `SIGHASH_SINGLE|ANYONECANPAY`.
+pub fn check_offer(offer_tx: &Transaction) -> Result<()> {
+    ensure!(
+        offer_tx.input.len() == 1 && offer_tx.output.len() == 1,
+        "offer must have exactly one input and one output"
+    );
+    ensure!(
+        offer_tx.version == Version::TWO
+            && offer_tx.lock_time == LockTime::ZERO
+            && offer_tx.input[0].sequence == Sequence::MAX,
+        "offer must be a version 2 transaction with no lock time"
+    );
+    // a DER-encoded ECDSA signature or a 64-byte Schnorr signature, followed by the sighash type
+    // (a bare 64-byte Schnorr signature has the default sighash type)
+    let signature = offer_tx.input[0].witness.nth(0).unwrap_or_default();
+    let sighash_type = match ecdsa::Signature::from_slice(signature) {
+        Ok(signature) => Some(signature.sighash_type as u32),
+        Err(_) if signature.len() == 65 => taproot::Signature::from_slice(signature)
+            .ok()
+            .map(|signature| signature.sighash_type as u32),
+        Err(_) => None,
+    };
+    ensure!(
+        sighash_type == Some(TapSighashType::SinglePlusAnyoneCanPay as u32),
+        "offer input must be signed with SIGHASH_SINGLE|ANYONECANPAY"
+    );
+    Ok(())
+}
+
+/// Create the spell completing the offer: pay the price to the maker and send all charms of the
+/// offered UTXO to `charms_destination`.
+/// `offered_utxo_spell` is the spell of the transaction creating the offered UTXO.
+pub fn take_spell(
+    offer_tx: &Transaction,
+    offered_utxo_spell: &Spell,
+    charms_destination: Output,
+) -> Result<Spell> {
+    check_offer(offer_tx)?;
+
+    let utxo = offer_tx.input[0].previous_output;
+    let charms = offered_utxo_spell
+        .outs
+        .get(utxo.vout as usize)
+        .and_then(|u| u.charms.clone())
+        .filter(|charms| !charms.is_empty())
+        .ok_or_else(|| anyhow!("offered UTXO {} has no charms", utxo))?;
+    let apps = offered_utxo_spell
+        .apps
+        .iter()
+        .filter(|(k, _)| charms.contains_key(*k))
+        .map(|(k, app)| (k.clone(), app.clone()))
+        .collect();
+
+    let payment = &offer_tx.output[0];
+
+    let mut spell = Spell::new();
+    spell.apps = apps;
+    spell.ins = vec![Input {
+        utxo_id: Some(UtxoId(TxId(utxo.txid.to_byte_array()), utxo.vout)),
+        charms: Some(charms.clone()),
+        sequence: None,
+    }];
+    spell.outs = vec![
+        Output {
+            script_pubkey: Some(payment.script_pubkey.clone()),
+            sats: Some(payment.value.to_sat()),
+            ..Default::default()
+        },
+        Output {
+            charms: Some(charms),
+            ..charms_destination
+        },
+    ];
+    Ok(spell)
+}
+
+/// Complete the swap: put the maker's signature from `offer_tx` into `spell_tx`.
+pub fn complete_swap_tx(spell_tx: &mut Transaction, offer_tx: &Transaction) -> Result<()> {
+    check_offer(offer_tx)?;
+    ensure!(
+        spell_tx.version == offer_tx.version && spell_tx.lock_time == offer_tx.lock_time,
+        "spell transaction version and lock time must match the offer"
+    );
+    ensure!(
+        spell_tx
+            .input
+            .first()
+            .is_some_and(
+                |tx_in| tx_in.previous_output == offer_tx.input[0].previous_output
+                    && tx_in.sequence == offer_tx.input[0].sequence
+            ),
+        "spell transaction must spend the offered UTXO in its first input"
+    );
+    ensure!(
+        spell_tx.output.first() == offer_tx.output.first(),
+        "spell transaction must pay the maker in its first output"
+    );
+
+    spell_tx.input[0].witness = offer_tx.input[0].witness.clone();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tx;
+    use bitcoin::{
+        secp256k1::{Message, Secp256k1, SecretKey},
+        EcdsaSighashType, Witness,
+    };
+    use charms_data::{App, Data};
+
+    const UTXO: &str = "f72700ac56bd4dd61f2ccb4acdf21d0b11bb294fc3efa9012b77903932197d2f:1";
+    const MAKER_SCRIPT: &str = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+    const TAKER_SCRIPT: &str =
+        "5120aa8b6f3b0e2a0c2a6b4bbdd5b0d4c1b6d0c2a8e8e3f1a6d7b9c0d1e2f3a4b5c6";
+
+    fn signed_offer(sighash_type: u8) -> Transaction {
+        let mut offer_tx = offer_tx(
+            UTXO.parse().unwrap(),
+            Amount::from_sat(50000),
+            ScriptBuf::from_hex(MAKER_SCRIPT).unwrap(),
+        );
+        let mut signature = vec![0u8; 64];
+        signature.push(sighash_type);
+        offer_tx.input[0].witness = Witness::from_slice(&[signature]);
+        offer_tx
+    }
+
+    #[test]
+    fn check_offer_sighash_type() {
+        assert!(check_offer(&signed_offer(0x83)).is_ok());
+        assert!(check_offer(&signed_offer(0x01)).is_err());
+
+        let mut unsigned = signed_offer(0x83);
+        unsigned.input[0].witness = Witness::new();
+        assert!(check_offer(&unsigned).is_err());
+    }
+
+    #[test]
+    fn check_offer_signature_shape() {
+        let mut offer_tx = signed_offer(0x83);
+
+        // a bare 64-byte Schnorr signature is SIGHASH_DEFAULT, whatever its last byte
+        let mut signature = vec![0u8; 63];
+        signature.push(0x83);
+        offer_tx.input[0].witness = Witness::from_slice(&[signature]);
+        assert!(check_offer(&offer_tx).is_err());
+
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[1u8; 32]).unwrap();
+        let signature = ecdsa::Signature {
+            signature: secp.sign_ecdsa(&Message::from_digest([2u8; 32]), &secret_key),
+            sighash_type: EcdsaSighashType::SinglePlusAnyoneCanPay,
+        };
+        offer_tx.input[0].witness = Witness::from_slice(&[signature.to_vec()]);
+        assert!(check_offer(&offer_tx).is_ok());
+
+        let signature = ecdsa::Signature {
+            sighash_type: EcdsaSighashType::All,
+            ..signature
+        };
+        offer_tx.input[0].witness = Witness::from_slice(&[signature.to_vec()]);
+        assert!(check_offer(&offer_tx).is_err());
+    }
+
+    #[test]
+    fn take_and_complete() {
+        let offer_tx = signed_offer(0x83);
+
+        let mut offered_utxo_spell = Spell::new();
+        offered_utxo_spell.apps = [
+            ("$00".to_string(), App::default()),
+            ("$01".to_string(), App::default()),
+        ]
+        .into();
+        offered_utxo_spell.outs = vec![
+            Output::default(),
+            Output {
+                charms: Some([("$01".to_string(), Data::from(&100u64))].into()),
+                ..Default::default()
+            },
+        ];
+
+        let destination = Output {
+            script_pubkey: Some(ScriptBuf::from_hex(TAKER_SCRIPT).unwrap()),
+            ..Default::default()
+        };
+        let spell = take_spell(&offer_tx, &offered_utxo_spell, destination).unwrap();
+        assert_eq!(spell.apps.keys().collect::<Vec<_>>(), vec!["$01"]);
+        assert_eq!(spell.outs[0].charms, None);
+        assert_eq!(spell.outs[1].charms, offered_utxo_spell.outs[1].charms);
+
+        let mut spell_tx = tx::from_spell(&spell).unwrap();
+        spell_tx.input.push(spell_tx.input[0].clone()); // the committed spell input
+        complete_swap_tx(&mut spell_tx, &offer_tx).unwrap();
+        assert_eq!(spell_tx.input[0].witness, offer_tx.input[0].witness);
+        assert_eq!(spell_tx.output[0], offer_tx.output[0]);
+
+        spell_tx.output.swap(0, 1);
+        assert!(complete_swap_tx(&mut spell_tx, &offer_tx).is_err());
+
+        assert!(take_spell(&offer_tx, &Spell::new(), Output::default()).is_err());
+    }
+}
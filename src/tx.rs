@@ -1,22 +1,27 @@
 use crate::{
-    script::{control_block, data_script, taproot_spend_info},
-    spell::{Input, Output, Spell},
+    script::{control_block, data_script, refund_script, taproot_spend_info, Refund},
+    spell::{Input, Network, Output, Spell},
     SPELL_VK,
 };
+use anyhow::{anyhow, bail, ensure};
 use bitcoin::{
     self,
     absolute::LockTime,
     hashes::Hash,
     key::Secp256k1,
+    psbt::Psbt,
+    script::PushBytesBuf,
     secp256k1::{rand::thread_rng, schnorr, Keypair, Message},
     sighash::{Prevouts, SighashCache},
     taproot,
-    taproot::LeafVersion,
+    taproot::{ControlBlock, LeafVersion},
     transaction::Version,
-    Amount, FeeRate, OutPoint, ScriptBuf, TapLeafHash, TapSighashType, Transaction, TxIn, TxOut,
-    Txid, Weight, Witness, XOnlyPublicKey,
+    Amount, FeeRate, OutPoint, ScriptBuf, Sequence, TapLeafHash, TapSighashType, Transaction, TxIn,
+    TxOut, Txid, Weight, Witness, XOnlyPublicKey,
 };
-use charms_client::NormalizedSpell;
+use charms_client::{tx::SpellEncoding, NormalizedSpell};
+use charms_data::App;
+use miniscript::{DefiniteDescriptorKey, Descriptor};
 use std::collections::BTreeMap;
 
 /// `add_spell` adds `spell` to `tx`:
@@ -26,12 +31,16 @@ use std::collections::BTreeMap;
 /// `fee_rate` is used to compute the amount of sats necessary to fund the commit and spell
 /// transactions.
 ///
+/// If `refund` is provided, the *committed spell* output can also be spent via its refund path, in
+/// case `tx` never confirms.
+///
 /// Return `[commit_tx, tx]`.
 ///
 /// Both `commit_tx` and `tx` need to be signed.
 pub fn add_spell(
     tx: Transaction,
     spell_data: &[u8],
+    spell_encoding: SpellEncoding,
     funding_out_point: OutPoint,
     funding_output_value: Amount,
     change_pubkey: ScriptBuf,
@@ -39,12 +48,13 @@ pub fn add_spell(
     prev_txs: &BTreeMap<Txid, Transaction>,
     charms_fee_pubkey: Option<ScriptBuf>,
     charms_fee: Amount,
+    refund: Option<Refund>,
 ) -> [Transaction; 2] {
     let secp256k1 = Secp256k1::new();
     let keypair = Keypair::new(&secp256k1, &mut thread_rng());
     let (public_key, _) = XOnlyPublicKey::from_keypair(&keypair);
 
-    let script = data_script(public_key, &spell_data);
+    let script = data_script(public_key, spell_encoding, spell_data);
 
     let commit_tx = create_commit_tx(
         funding_out_point,
@@ -52,6 +62,7 @@ pub fn add_spell(
         public_key,
         &script,
         fee_rate,
+        refund.as_ref(),
     );
     let commit_txout = &commit_tx.output[0];
 
@@ -64,8 +75,14 @@ pub fn add_spell(
     }
 
     let script_len = script.len();
-    let change_amount =
-        compute_change_amount(fee_rate, script_len, &tx, prev_txs, commit_txout.value);
+    let change_amount = compute_change_amount(
+        fee_rate,
+        script_len,
+        refund.is_some(),
+        &tx,
+        prev_txs,
+        commit_txout.value,
+    );
 
     modify_tx(
         &mut tx,
@@ -82,6 +99,7 @@ pub fn add_spell(
         public_key,
         script,
         signature,
+        refund.as_ref(),
     );
 
     dbg!((
@@ -104,11 +122,14 @@ pub fn add_spell(
 fn compute_change_amount(
     fee_rate: FeeRate,
     script_len: usize,
+    has_refund: bool,
     tx: &Transaction,
     prev_txs: &BTreeMap<Txid, Transaction>,
     commit_txout_value: Amount,
 ) -> Amount {
-    let script_input_weight = Weight::from_wu(script_len as u64 + 268);
+    // the control block has the refund leaf hash if there is a refund path
+    let control_block_extra_len = if has_refund { 32 } else { 0 };
+    let script_input_weight = Weight::from_wu(script_len as u64 + 268 + control_block_extra_len);
     let change_output_weight = Weight::from_wu(172);
     let signatures_weight = Weight::from_wu(66) * tx.input.len() as u64;
 
@@ -131,6 +152,7 @@ fn create_commit_tx(
     public_key: XOnlyPublicKey,
     script: &ScriptBuf,
     fee_rate: FeeRate,
+    refund: Option<&Refund>,
 ) -> Transaction {
     let fee = fee_rate.fee_vb(111).unwrap(); // tx is 111 vbytes when spending a Taproot output
 
@@ -146,7 +168,7 @@ fn create_commit_tx(
         output: vec![TxOut {
             value: funding_output_value - fee,
             script_pubkey: ScriptBuf::new_p2tr_tweaked(
-                taproot_spend_info(public_key, script.clone()).output_key(),
+                taproot_spend_info(public_key, script.clone(), refund).output_key(),
             ),
         }],
     };
@@ -210,6 +232,7 @@ fn append_witness_data(
     public_key: XOnlyPublicKey,
     script: ScriptBuf,
     signature: schnorr::Signature,
+    refund: Option<&Refund>,
 ) {
     witness.push(
         taproot::Signature {
@@ -219,7 +242,7 @@ fn append_witness_data(
         .to_vec(),
     );
     witness.push(script.clone());
-    witness.push(control_block(public_key, script).serialize());
+    witness.push(control_block(public_key, script, refund).serialize());
 }
 
 #[tracing::instrument(level = "debug", skip_all)]
@@ -232,14 +255,131 @@ pub fn norm_spell(tx: &Transaction) -> Option<NormalizedSpell> {
         .ok()
 }
 
+/// Extract and verify the spell in `tx`, keying the apps found in `app_keys` by their keys there.
 #[tracing::instrument(level = "debug", skip_all)]
-pub fn spell(tx: &Transaction) -> Option<Spell> {
+pub fn spell(tx: &Transaction, app_keys: &BTreeMap<App, String>) -> Option<Spell> {
     match norm_spell(tx) {
-        Some(norm_spell) => Some(Spell::denormalized(&norm_spell)),
+        Some(norm_spell) => Some(Spell::denormalized(&norm_spell, app_keys)),
         None => None,
     }
 }
 
+/// Create a PSBT for `tx`, for its inputs to be signed by multiple parties.
+/// Witnesses already in `tx` (e.g. of the *committed spell* input) are kept as final.
+/// Inputs get `witness_utxo` and `non_witness_utxo` from `prev_txs` if there: signing Taproot
+/// inputs needs all the spent outputs, and some signers need the whole previous transaction.
+pub fn to_psbt(tx: &Transaction, prev_txs: &BTreeMap<Txid, Transaction>) -> anyhow::Result<Psbt> {
+    let mut unsigned_tx = tx.clone();
+    for tx_in in unsigned_tx.input.iter_mut() {
+        tx_in.script_sig = ScriptBuf::new();
+        tx_in.witness = Witness::new();
+    }
+    let mut psbt = Psbt::from_unsigned_tx(unsigned_tx)?;
+    for (psbt_input, tx_in) in psbt.inputs.iter_mut().zip(&tx.input) {
+        let OutPoint { txid, vout } = tx_in.previous_output;
+        let prev_tx = prev_txs.get(&txid);
+        psbt_input.witness_utxo = prev_tx
+            .and_then(|prev_tx| prev_tx.output.get(vout as usize))
+            .cloned();
+        psbt_input.non_witness_utxo = prev_tx.cloned();
+        if !tx_in.witness.is_empty() {
+            psbt_input.final_script_witness = Some(tx_in.witness.clone());
+        }
+    }
+    Ok(psbt)
+}
+
+/// Create a PSBT spending the *committed spell* output of `commit_tx` via its refund path, in case
+/// the spell transaction never confirms: it pays the output's value (minus the fee at `fee_rate`)
+/// to `destination_script_pubkey`.
+///
+/// `spell_tx` is the spell transaction spending the *committed spell* output: the refund path's
+/// control block is rebuilt from its spell script and Taproot internal key, and `refund`.
+///
+/// The input is to be signed with the refund key (e.g. `psbt sign`). The transaction can only be
+/// mined once `commit_tx` is `refund.timeout` blocks deep.
+pub fn refund_psbt(
+    commit_tx: &Transaction,
+    spell_tx: &Transaction,
+    refund: &Refund,
+    destination_script_pubkey: ScriptBuf,
+    fee_rate: FeeRate,
+) -> anyhow::Result<Psbt> {
+    let commit_out_point = OutPoint {
+        txid: commit_tx.compute_txid(),
+        vout: 0,
+    };
+    let commit_txout = commit_tx
+        .output
+        .first()
+        .ok_or_else(|| anyhow!("commit transaction has no outputs"))?;
+
+    let spell_witness = spell_tx
+        .input
+        .iter()
+        .find(|tx_in| tx_in.previous_output == commit_out_point)
+        .map(|tx_in| &tx_in.witness)
+        .ok_or_else(|| {
+            anyhow!(
+                "spell transaction does not spend the committed spell output {}",
+                commit_out_point
+            )
+        })?;
+    let (Some(script), Some(control_block)) = (
+        spell_witness.tapscript(),
+        spell_witness.taproot_control_block(),
+    ) else {
+        bail!("spell transaction does not spend the committed spell output via the spell script");
+    };
+    let internal_key = ControlBlock::decode(control_block)?.internal_key;
+
+    let spend_info = taproot_spend_info(internal_key, script.to_owned(), Some(refund));
+    ensure!(
+        ScriptBuf::new_p2tr_tweaked(spend_info.output_key()) == commit_txout.script_pubkey,
+        "the committed spell output has no refund path for this refund key and timeout"
+    );
+    let refund_script = refund_script(refund);
+    let control_block = spend_info
+        .control_block(&(refund_script.clone(), LeafVersion::TapScript))
+        .expect("the refund script should be a leaf of the Taproot tree");
+
+    let mut tx = Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: commit_out_point,
+            script_sig: Default::default(),
+            sequence: refund.sequence(),
+            // placeholder signature: for the fee to cover the final witness
+            witness: Witness::from_slice(&[
+                &[0u8; 64][..],
+                refund_script.as_bytes(),
+                &control_block.serialize()[..],
+            ]),
+        }],
+        output: vec![TxOut {
+            value: commit_txout.value,
+            script_pubkey: destination_script_pubkey,
+        }],
+    };
+    let fee = fee_rate.fee_wu(tx.weight()).unwrap();
+    tx.output[0].value = commit_txout
+        .value
+        .checked_sub(fee)
+        .ok_or_else(|| anyhow!("the committed spell output can't pay the fee of {}", fee))?;
+    tx.input[0].witness = Witness::new();
+
+    let mut psbt = Psbt::from_unsigned_tx(tx)?;
+    let psbt_input = &mut psbt.inputs[0];
+    psbt_input.witness_utxo = Some(commit_txout.clone());
+    psbt_input.tap_internal_key = Some(internal_key);
+    psbt_input.tap_merkle_root = spend_info.merkle_root();
+    psbt_input
+        .tap_scripts
+        .insert(control_block, (refund_script, LeafVersion::TapScript));
+    Ok(psbt)
+}
+
 pub fn txs_by_txid(prev_txs: Vec<Transaction>) -> BTreeMap<Txid, Transaction> {
     prev_txs
         .into_iter()
@@ -259,26 +399,49 @@ pub fn tx_total_amount_out(tx: &Transaction) -> Amount {
     tx.output.iter().map(|tx_out| tx_out.value).sum::<Amount>()
 }
 
-pub fn tx_output(outs: &[Output]) -> Vec<TxOut> {
+pub fn tx_output(outs: &[Output], network: Network) -> anyhow::Result<Vec<TxOut>> {
     outs.iter()
         .map(|u| {
-            let value = Amount::from_sat(u.sats.unwrap_or(1000)); // TODO make a constant
-            let address = u
-                .address
-                .as_ref()
-                .expect("address should be provided")
-                .clone()
-                .assume_checked();
-            let script_pubkey = ScriptBuf::from(address.script_pubkey());
-            TxOut {
+            let default_sats = match u.is_op_return() {
+                true => 0,
+                false => 1000, // TODO make a constant
+            };
+            let value = Amount::from_sat(u.sats.unwrap_or(default_sats));
+            let script_pubkey = output_script_pubkey(u, network)?;
+            Ok(TxOut {
                 value,
                 script_pubkey,
-            }
+            })
         })
         .collect()
 }
 
-pub fn tx_input(ins: &[Input]) -> Vec<TxIn> {
+/// Get the `script_pubkey` for a spell output from its destination: `address`, `script_pubkey`,
+/// `descriptor` or `op_return` data. Exactly one of them must be provided.
+/// Addresses must be valid for the `network`.
+pub fn output_script_pubkey(u: &Output, network: Network) -> anyhow::Result<ScriptBuf> {
+    match (&u.address, &u.script_pubkey, &u.descriptor, &u.op_return) {
+        (Some(address), None, None, None) => Ok(network.check_address(address)?.script_pubkey()),
+        (None, Some(script_pubkey), None, None) => Ok(script_pubkey.clone()),
+        (None, None, Some(descriptor), None) => {
+            let descriptor: Descriptor<DefiniteDescriptorKey> = descriptor
+                .parse()
+                .map_err(|e| anyhow!("invalid output descriptor {}: {}", descriptor, e))?;
+            Ok(descriptor.script_pubkey())
+        }
+        (None, None, None, Some(data)) => {
+            let data = PushBytesBuf::try_from(data.clone())
+                .map_err(|e| anyhow!("invalid op_return data: {}", e))?;
+            Ok(ScriptBuf::new_op_return(data))
+        }
+        (None, None, None, None) => {
+            bail!("output must have one of: address, script_pubkey, descriptor, op_return")
+        }
+        _ => bail!("output must have only one of: address, script_pubkey, descriptor, op_return"),
+    }
+}
+
+pub fn tx_input(ins: &[Input], default_sequence: Sequence) -> Vec<TxIn> {
     ins.iter()
         .map(|u| {
             let utxo_id = u.utxo_id.as_ref().unwrap();
@@ -288,22 +451,231 @@ pub fn tx_input(ins: &[Input]) -> Vec<TxIn> {
                     vout: utxo_id.1,
                 },
                 script_sig: Default::default(),
-                sequence: Default::default(),
+                sequence: u.sequence.map(Sequence).unwrap_or(default_sequence),
                 witness: Default::default(),
             }
         })
         .collect()
 }
 
-pub fn from_spell(spell: &Spell) -> Transaction {
-    let input = tx_input(&spell.ins);
-    let output = tx_output(&spell.outs);
+pub fn from_spell(spell: &Spell) -> anyhow::Result<Transaction> {
+    let input = tx_input(&spell.ins, spell.default_sequence());
+    let output = tx_output(&spell.outs, spell.network())?;
 
     let tx = Transaction {
         version: Version::TWO,
-        lock_time: LockTime::ZERO,
+        lock_time: LockTime::from_consensus(spell.lock_time.unwrap_or_default()),
         input,
         output,
     };
-    tx
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use charms_client::{
+        tx::{encode_spell_data, parse_spell_and_proof},
+        NormalizedTransaction, SpellError, CURRENT_VERSION, V3,
+    };
+
+    #[test]
+    fn psbt_keeps_final_witnesses() {
+        let prev_tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: Amount::from_sat(1000),
+                script_pubkey: ScriptBuf::new_op_return([1u8]),
+            }],
+        };
+        let prev_txid = prev_tx.compute_txid();
+
+        let tx_in = |txid, witness: &[&[u8]]| TxIn {
+            previous_output: OutPoint { txid, vout: 0 },
+            script_sig: Default::default(),
+            sequence: Sequence::MAX,
+            witness: Witness::from_slice(witness),
+        };
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![
+                tx_in(Txid::all_zeros(), &[]),
+                tx_in(prev_txid, &[b"signature", b"script"]),
+            ],
+            output: vec![],
+        };
+
+        let psbt = to_psbt(&tx, &txs_by_txid(vec![prev_tx.clone()])).unwrap();
+        assert_eq!(psbt.unsigned_tx.compute_txid(), tx.compute_txid());
+        assert!(psbt.unsigned_tx.input.iter().all(|i| i.witness.is_empty()));
+
+        assert_eq!(psbt.inputs[0].witness_utxo, None);
+        assert_eq!(psbt.inputs[0].non_witness_utxo, None);
+        assert_eq!(psbt.inputs[0].final_script_witness, None);
+        assert_eq!(psbt.inputs[1].witness_utxo, Some(prev_tx.output[0].clone()));
+        assert_eq!(psbt.inputs[1].non_witness_utxo, Some(prev_tx.clone()));
+        assert_eq!(
+            psbt.inputs[1].final_script_witness,
+            Some(tx.input[1].witness.clone())
+        );
+    }
+
+    #[test]
+    fn refund_spend() {
+        let secp256k1 = Secp256k1::new();
+        let refund_keypair = Keypair::new(&secp256k1, &mut thread_rng());
+        let refund = Refund {
+            pubkey: refund_keypair.x_only_public_key().0,
+            timeout: 144,
+        };
+
+        let prev_tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![TxOut {
+                value: Amount::from_sat(10000),
+                script_pubkey: ScriptBuf::new_op_return([1u8]),
+            }],
+        };
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: prev_tx.compute_txid(),
+                    vout: 0,
+                },
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(5000),
+                script_pubkey: ScriptBuf::new_op_return([2u8]),
+            }],
+        };
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let [commit_tx, spell_tx] = add_spell(
+            tx,
+            b"spell",
+            SpellEncoding::Cbor,
+            OutPoint::null(),
+            Amount::from_sat(20000),
+            ScriptBuf::new_op_return([3u8]),
+            fee_rate,
+            &txs_by_txid(vec![prev_tx]),
+            None,
+            Amount::ZERO,
+            Some(refund),
+        );
+
+        let destination = ScriptBuf::new_op_return([4u8]);
+        let psbt = refund_psbt(
+            &commit_tx,
+            &spell_tx,
+            &refund,
+            destination.clone(),
+            fee_rate,
+        )
+        .unwrap();
+        let mut refund_tx = psbt.unsigned_tx.clone();
+        assert_eq!(refund_tx.input[0].sequence, refund.sequence());
+
+        // sign with the refund key, as a wallet would with the PSBT
+        let psbt_input = &psbt.inputs[0];
+        let (control_block, (script, leaf_version)) =
+            psbt_input.tap_scripts.first_key_value().unwrap();
+        let prevouts = [psbt_input.witness_utxo.clone().unwrap()];
+        let sighash = SighashCache::new(&refund_tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&prevouts),
+                TapLeafHash::from_script(script, *leaf_version),
+                TapSighashType::Default,
+            )
+            .unwrap();
+        let message = Message::from_digest(sighash.to_byte_array());
+        let signature = secp256k1.sign_schnorr(&message, &refund_keypair);
+        refund_tx.input[0].witness = Witness::from_slice(&[
+            &signature.serialize()[..],
+            script.as_bytes(),
+            &control_block.serialize()[..],
+        ]);
+
+        // the witness spends the refund leaf of the committed spell output
+        let output_key =
+            XOnlyPublicKey::from_slice(&commit_tx.output[0].script_pubkey.as_bytes()[2..]).unwrap();
+        assert!(control_block.verify_taproot_commitment(&secp256k1, output_key, script));
+        assert!(secp256k1
+            .verify_schnorr(&signature, &message, &refund.pubkey)
+            .is_ok());
+        // the fee covers the final witness
+        assert!(
+            fee_rate.fee_wu(refund_tx.weight()).unwrap()
+                <= commit_tx.output[0].value - refund_tx.output[0].value
+        );
+
+        let other_refund = Refund {
+            timeout: 1,
+            ..refund
+        };
+        assert!(refund_psbt(&commit_tx, &spell_tx, &other_refund, destination, fee_rate).is_err());
+    }
+
+    #[test]
+    fn refund_spell_extraction() {
+        let refund = Refund {
+            pubkey: Keypair::new(&Secp256k1::new(), &mut thread_rng())
+                .x_only_public_key()
+                .0,
+            timeout: 144,
+        };
+        let spell_tx_with_refund = |norm_spell: &NormalizedSpell| {
+            let (spell_encoding, spell_data) = encode_spell_data(norm_spell, b"proof");
+            let [_, spell_tx] = add_spell(
+                Transaction {
+                    version: Version::TWO,
+                    lock_time: LockTime::ZERO,
+                    input: vec![],
+                    output: vec![],
+                },
+                &spell_data,
+                spell_encoding,
+                OutPoint::null(),
+                Amount::from_sat(20000),
+                ScriptBuf::new_op_return([3u8]),
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                &BTreeMap::new(),
+                None,
+                Amount::ZERO,
+                Some(refund),
+            );
+            spell_tx
+        };
+
+        let mut norm_spell = NormalizedSpell {
+            version: V3,
+            tx: NormalizedTransaction {
+                ins: None,
+                refs: Default::default(),
+                outs: vec![],
+            },
+            app_public_inputs: Default::default(),
+        };
+        let spell_tx = spell_tx_with_refund(&norm_spell);
+        assert_eq!(
+            parse_spell_and_proof(spell_tx.input.last().unwrap()),
+            Ok((norm_spell.clone(), b"proof".to_vec().into()))
+        );
+
+        // earlier versions only support a single-leaf Taproot tree: the spell would be lost
+        norm_spell.version = CURRENT_VERSION;
+        let spell_tx = spell_tx_with_refund(&norm_spell);
+        assert!(matches!(
+            parse_spell_and_proof(spell_tx.input.last().unwrap()),
+            Err(SpellError::MalformedEnvelope(_))
+        ));
+    }
 }
@@ -15,33 +15,33 @@ pub fn str_index(i: &usize) -> String {
 }
 
 pub struct AsyncShared<T> {
-    pub create: fn() -> T,
+    pub create: Box<dyn Fn() -> T + Send + Sync>,
     pub instance: OnceCell<T>,
 }
 
 impl<T> AsyncShared<T> {
-    pub fn new(create: fn() -> T) -> Self {
+    pub fn new(create: impl Fn() -> T + Send + Sync + 'static) -> Self {
         Self {
-            create,
+            create: Box::new(create),
             instance: OnceCell::new(),
         }
     }
 
     pub async fn get(&self) -> &T {
-        let create = self.create;
+        let create = &self.create;
         self.instance.get_or_init(|| async { create() }).await
     }
 }
 
 pub struct Shared<T> {
-    pub create: fn() -> T,
+    pub create: Box<dyn Fn() -> T + Send + Sync>,
     pub instance: OnceLock<T>,
 }
 
 impl<T> Shared<T> {
-    pub fn new(create: fn() -> T) -> Self {
+    pub fn new(create: impl Fn() -> T + Send + Sync + 'static) -> Self {
         Self {
-            create,
+            create: Box::new(create),
             instance: OnceLock::new(),
         }
     }